@@ -0,0 +1,208 @@
+// Dates and times as stored in directory entries. Dates count years from 1980 and times have a
+// resolution of 2 seconds, refined to 10 milliseconds for creation times.
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const YEAR_OFFSET: u16 = 1980;
+const MAX_YEAR: u16 = YEAR_OFFSET + 127;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The (year, month, day) of a number of days since the unix epoch, using the algorithm from
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + (month <= 2) as u64;
+    (year, month, day)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FatDate {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl FatDate {
+    /// Returns `None` for the zero date, which marks an unset field, and for invalid dates
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        let year = YEAR_OFFSET + (raw >> 9);
+        let month = ((raw >> 5) & 0xF) as u8;
+        let day = (raw & 0x1F) as u8;
+        if !(1..=12).contains(&month) || day == 0 {
+            return None;
+        }
+        Some(Self { year, month, day })
+    }
+
+    pub fn raw(&self) -> u16 {
+        ((self.year - YEAR_OFFSET) << 9) | ((self.month as u16) << 5) | self.day as u16
+    }
+}
+
+impl fmt::Display for FatDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FatTime {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millisecond: u16,
+}
+
+impl FatTime {
+    /// `tenth` is the count of 10 millisecond units stored alongside creation times. It is 0 for
+    /// other times.
+    pub fn from_raw(raw: u16, tenth: u8) -> Option<Self> {
+        let hour = (raw >> 11) as u8;
+        let minute = ((raw >> 5) & 0x3F) as u8;
+        let two_seconds = (raw & 0x1F) as u8;
+        if hour > 23 || minute > 59 || two_seconds > 29 || tenth > 199 {
+            return None;
+        }
+        Some(Self {
+            hour,
+            minute,
+            second: two_seconds * 2 + tenth / 100,
+            millisecond: (tenth % 100) as u16 * 10,
+        })
+    }
+
+    pub fn raw(&self) -> u16 {
+        ((self.hour as u16) << 11) | ((self.minute as u16) << 5) | (self.second as u16 / 2)
+    }
+
+    pub fn raw_tenth(&self) -> u8 {
+        (self.second % 2) * 100 + (self.millisecond / 10) as u8
+    }
+}
+
+impl fmt::Display for FatTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            self.hour, self.minute, self.second, self.millisecond
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FatDateTime {
+    pub date: FatDate,
+    pub time: FatTime,
+}
+
+impl FatDateTime {
+    pub fn from_raw(date: u16, time: u16, tenth: u8) -> Option<Self> {
+        Some(Self {
+            date: FatDate::from_raw(date)?,
+            time: FatTime::from_raw(time, tenth)?,
+        })
+    }
+
+    /// Times are in UTC. Times outside the range which can be represented (the years 1980 to
+    /// 2107) are clamped to that range.
+    pub fn from_system_time(system_time: SystemTime) -> Self {
+        let since_epoch = system_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+        if year < YEAR_OFFSET as u64 {
+            return Self::MIN;
+        }
+        if year > MAX_YEAR as u64 {
+            return Self::MAX;
+        }
+        let second_of_day = seconds % SECONDS_PER_DAY;
+        Self {
+            date: FatDate {
+                year: year as u16,
+                month,
+                day,
+            },
+            time: FatTime {
+                hour: (second_of_day / 3600) as u8,
+                minute: ((second_of_day / 60) % 60) as u8,
+                second: (second_of_day % 60) as u8,
+                millisecond: (since_epoch.subsec_millis() / 10 * 10) as u16,
+            },
+        }
+    }
+
+    const MIN: Self = Self {
+        date: FatDate {
+            year: YEAR_OFFSET,
+            month: 1,
+            day: 1,
+        },
+        time: FatTime {
+            hour: 0,
+            minute: 0,
+            second: 0,
+            millisecond: 0,
+        },
+    };
+
+    const MAX: Self = Self {
+        date: FatDate {
+            year: MAX_YEAR,
+            month: 12,
+            day: 31,
+        },
+        time: FatTime {
+            hour: 23,
+            minute: 59,
+            second: 59,
+            millisecond: 990,
+        },
+    };
+}
+
+impl fmt::Display for FatDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.date, self.time)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        // 2021-06-15 13:45:31.250
+        let date = (41 << 9) | (6 << 5) | 15;
+        let time = (13 << 11) | (45 << 5) | 15;
+        let date_time = FatDateTime::from_raw(date, time, 125).unwrap();
+        assert_eq!(date_time.to_string(), "2021-06-15 13:45:31.250");
+        assert_eq!(date_time.date.raw(), date);
+        assert_eq!(date_time.time.raw(), time);
+        assert_eq!(date_time.time.raw_tenth(), 125);
+        assert_eq!(FatDate::from_raw(0), None);
+    }
+
+    #[test]
+    fn from_system_time() {
+        use std::time::Duration;
+        let at = |seconds, millis| {
+            FatDateTime::from_system_time(
+                UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_millis(millis),
+            )
+            .to_string()
+        };
+        assert_eq!(at(1623764731, 257), "2021-06-15 13:45:31.250");
+        assert_eq!(at(951782400, 0), "2000-02-29 00:00:00.000");
+        assert_eq!(at(0, 0), "1980-01-01 00:00:00.000");
+        assert_eq!(at(1 << 40, 0), "2107-12-31 23:59:59.990");
+    }
+}
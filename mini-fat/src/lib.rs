@@ -4,15 +4,36 @@ use std::fs::File;
 use std::io;
 use std::ops::Range;
 use std::path;
+use std::time::SystemTime;
+
+mod date_time;
+mod short_name;
+mod writer;
+
+pub use create::SOURCE_DATE_EPOCH;
+pub use date_time::{FatDate, FatDateTime, FatTime};
+pub use writer::{FatFileWriter, FatWriter};
 
 mod create {
-    pub const BYTES_PER_SECTOR: u16 = 512;
-    pub const SECTORS_PER_CLUSTER: u8 = 1;
-    pub const BYTES_PER_CLUSTER: u32 = BYTES_PER_SECTOR as u32 * SECTORS_PER_CLUSTER as u32;
-    pub const FAT_ENTRY_SIZE_BYTES: u8 = 4; // all created images will be formatted as FAT32
-    pub const MIN_NUM_CLUSTERS: u64 = 65526; // 1 larger than the technical minimum for FAT32 to mitigate off-by-1 errors in firmware
+    use super::{Error, FatError, FatType, DIRECTORY_ENTRY_BYTES};
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub const DEFAULT_BYTES_PER_SECTOR: u16 = 512;
+    pub const VALID_BYTES_PER_SECTOR: &[u16] = &[512, 1024, 2048, 4096];
+    pub const MAX_SECTORS_PER_CLUSTER: u8 = 128;
+    pub const MAX_DEFAULT_BYTES_PER_CLUSTER: u32 = 32 * 1024;
+    // Cluster counts are kept 1 away from the boundaries between FAT types to mitigate off-by-1
+    // errors in firmware
+    pub const MAX_NUM_CLUSTERS_FAT12: u32 = 4083;
+    pub const MIN_NUM_CLUSTERS_FAT16: u32 = 4086;
+    pub const MAX_NUM_CLUSTERS_FAT16: u32 = 65523;
+    pub const MIN_NUM_CLUSTERS_FAT32: u32 = 65526;
+    pub const MAX_NUM_CLUSTERS_FAT32: u32 = 0x0FFFFFF4;
     pub const NUM_FATS: u8 = 2;
-    pub const RESERVED_SECTOR_COUNT: u16 = 32;
+    pub const RESERVED_SECTOR_COUNT_FAT12_FAT16: u16 = 1; // copied from mkfs.fat
+    pub const RESERVED_SECTOR_COUNT_FAT32: u16 = 32;
+    pub const MIN_ROOT_ENTRY_COUNT: u16 = 512; // copied from mkfs.fat
     pub const JMP_BOOT: [u8; 3] = [235, 88, 144]; // copied from mkfs.fat
     pub const OEM_NAME: &str = "mini_fat";
     pub const MEDIA_FIXED: u8 = 0xF8;
@@ -24,8 +45,267 @@ mod create {
     pub const FS_INFO: u16 = 1;
     pub const BK_BOOT_SECTOR: u16 = 6;
     pub const DRIVE_NUM: u8 = 128; // copied from mkfs.fat
-    pub const VOLUME_LABEL: &str = "NO NAME    "; // copied from mkfs.fat
+    pub const VOLUME_LABEL: &str = "NO NAME"; // copied from mkfs.fat
+    pub const FAT12_FILE_SYSTEM_TYPE: &str = "FAT12   "; // copied from mkfs.fat
+    pub const FAT16_FILE_SYSTEM_TYPE: &str = "FAT16   "; // copied from mkfs.fat
     pub const FAT32_FILE_SYSTEM_TYPE: &str = "FAT32   "; // copied from mkfs.fat
+    pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+    /// The time to use in place of the current time. This is the value of the environment
+    /// variable `SOURCE_DATE_EPOCH` (seconds since the unix epoch) if it is set, so that images
+    /// can be reproduced (https://reproducible-builds.org/specs/source-date-epoch/).
+    pub fn current_time() -> Result<SystemTime, Error> {
+        match std::env::var(SOURCE_DATE_EPOCH) {
+            Ok(source_date_epoch) => current_time_from(Some(&source_date_epoch)),
+            Err(std::env::VarError::NotPresent) => current_time_from(None),
+            Err(std::env::VarError::NotUnicode(source_date_epoch)) => Err(
+                FatError::InvalidSourceDateEpoch(source_date_epoch.to_string_lossy().to_string())
+                    .into(),
+            ),
+        }
+    }
+
+    /// The time to use in place of the current time, given the value of `SOURCE_DATE_EPOCH`
+    pub fn current_time_from(source_date_epoch: Option<&str>) -> Result<SystemTime, Error> {
+        match source_date_epoch {
+            Some(source_date_epoch) => source_date_epoch
+                .trim()
+                .parse()
+                .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+                .map_err(|_| {
+                    FatError::InvalidSourceDateEpoch(source_date_epoch.to_string()).into()
+                }),
+            None => Ok(SystemTime::now()),
+        }
+    }
+
+    pub fn volume_id_from_time(time: SystemTime) -> u32 {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|e| e.duration())
+            .as_millis() as u32
+    }
+
+    pub fn validate_bytes_per_sector(bytes_per_sector: u16) -> Result<(), Error> {
+        if VALID_BYTES_PER_SECTOR.contains(&bytes_per_sector) {
+            Ok(())
+        } else {
+            Err(FatError::InvalidBytesPerSector(bytes_per_sector).into())
+        }
+    }
+
+    pub fn validate_sectors_per_cluster(sectors_per_cluster: u8) -> Result<(), Error> {
+        if sectors_per_cluster.is_power_of_two() && sectors_per_cluster <= MAX_SECTORS_PER_CLUSTER {
+            Ok(())
+        } else {
+            Err(FatError::InvalidSectorsPerCluster(sectors_per_cluster).into())
+        }
+    }
+
+    /// Picks a cluster size for a volume of a given size in the same way as mkfs.fat. FAT32
+    /// volumes use the table from Microsoft's FAT specification. FAT12 and FAT16 volumes start
+    /// with 2KiB clusters, which are doubled until the volume fits in the maximum number of
+    /// clusters. When no FAT type is given, volumes of at least 512MiB are assumed to be FAT32.
+    pub fn default_bytes_per_cluster(fat_type: Option<FatType>, volume_size_in_bytes: u64) -> u32 {
+        const KIB: u64 = 1 << 10;
+        const MIB: u64 = 1 << 20;
+        const GIB: u64 = 1 << 30;
+        let fat_type = fat_type.unwrap_or(if volume_size_in_bytes >= 512 * MIB {
+            FatType::Fat32
+        } else {
+            FatType::Fat16
+        });
+        let max_num_clusters = match fat_type {
+            FatType::Fat12 => MAX_NUM_CLUSTERS_FAT12,
+            FatType::Fat16 => MAX_NUM_CLUSTERS_FAT16,
+            FatType::Fat32 => {
+                return if volume_size_in_bytes > 32 * GIB {
+                    32 * KIB as u32
+                } else if volume_size_in_bytes > 16 * GIB {
+                    16 * KIB as u32
+                } else if volume_size_in_bytes > 8 * GIB {
+                    8 * KIB as u32
+                } else if volume_size_in_bytes > 260 * MIB {
+                    4 * KIB as u32
+                } else {
+                    512
+                };
+            }
+        };
+        let mut bytes_per_cluster = 2 * KIB as u32;
+        while bytes_per_cluster < MAX_DEFAULT_BYTES_PER_CLUSTER
+            && volume_size_in_bytes / bytes_per_cluster as u64 > max_num_clusters as u64
+        {
+            bytes_per_cluster *= 2;
+        }
+        bytes_per_cluster
+    }
+
+    /// FAT types in the order they are tried when the type isn't specified
+    pub const FAT_TYPES_SMALLEST_FIRST: &[FatType] =
+        &[FatType::Fat12, FatType::Fat16, FatType::Fat32];
+
+    /// The sizes of the regions of a FAT partition
+    #[derive(Debug)]
+    pub struct Layout {
+        pub fat_type: FatType,
+        pub bytes_per_sector: u16,
+        pub sectors_per_cluster: u8,
+        pub reserved_sector_count: u16,
+        pub root_entry_count: u16,
+        pub fat_size_in_sectors: u32,
+        pub num_data_clusters: u32,
+        pub num_used_data_clusters: u32,
+        pub total_num_sectors: u64,
+    }
+
+    impl Layout {
+        /// If `total_num_sectors` is `None`, the partition is made just large enough to hold
+        /// `num_used_data_clusters` and meet the minimum cluster count of `fat_type`. Otherwise the
+        /// partition is given that many sectors, and the data region is filled with as many
+        /// clusters as will fit.
+        pub fn new(
+            fat_type: FatType,
+            bytes_per_sector: u16,
+            sectors_per_cluster: u8,
+            num_used_data_clusters: u32,
+            num_root_directory_entries: u32,
+            total_num_sectors: Option<u64>,
+        ) -> Result<Self, Error> {
+            let (min_num_clusters, max_num_clusters) = match fat_type {
+                FatType::Fat12 => (1, MAX_NUM_CLUSTERS_FAT12),
+                FatType::Fat16 => (MIN_NUM_CLUSTERS_FAT16, MAX_NUM_CLUSTERS_FAT16),
+                FatType::Fat32 => (MIN_NUM_CLUSTERS_FAT32, MAX_NUM_CLUSTERS_FAT32),
+            };
+            let (reserved_sector_count, root_entry_count) = match fat_type {
+                FatType::Fat12 | FatType::Fat16 => {
+                    // the root directory region is a whole number of sectors
+                    let entries_per_sector = bytes_per_sector as u32 / DIRECTORY_ENTRY_BYTES;
+                    let root_entry_count = u16::try_from(
+                        num_root_directory_entries
+                            .next_multiple_of(entries_per_sector)
+                            .max(MIN_ROOT_ENTRY_COUNT as u32),
+                    )
+                    .map_err(|_| {
+                        FatError::TooManyRootDirectoryEntries(num_root_directory_entries)
+                    })?;
+                    (RESERVED_SECTOR_COUNT_FAT12_FAT16, root_entry_count)
+                }
+                FatType::Fat32 => (RESERVED_SECTOR_COUNT_FAT32, 0),
+            };
+            let root_directory_sectors =
+                root_entry_count as u64 * DIRECTORY_ENTRY_BYTES as u64 / bytes_per_sector as u64;
+            // Add 2 to the number of clusters to account for the fact that the first 2 FAT
+            // entries are unused.
+            let fat_size_in_sectors = |num_clusters: u64| {
+                fat_type
+                    .fat_size_in_bytes((num_clusters + 2).min(u32::MAX as u64) as u32)
+                    .div_ceil(bytes_per_sector as u64)
+            };
+            let overhead_num_sectors = reserved_sector_count as u64 + root_directory_sectors;
+            let (num_data_clusters, fat_size_in_sectors, total_num_sectors) =
+                match total_num_sectors {
+                    None => {
+                        if num_used_data_clusters > max_num_clusters {
+                            return Err(FatError::TooManyClusters {
+                                fat_type,
+                                num_clusters: num_used_data_clusters,
+                            }
+                            .into());
+                        }
+                        let num_data_clusters = num_used_data_clusters.max(min_num_clusters) as u64;
+                        let fat_size_in_sectors = fat_size_in_sectors(num_data_clusters);
+                        let total_num_sectors = overhead_num_sectors
+                            + (fat_size_in_sectors * NUM_FATS as u64)
+                            + (num_data_clusters * sectors_per_cluster as u64);
+                        (num_data_clusters, fat_size_in_sectors, total_num_sectors)
+                    }
+                    Some(total_num_sectors) => {
+                        let available_num_sectors =
+                            total_num_sectors.saturating_sub(overhead_num_sectors);
+                        // The FAT is sized for the number of clusters there would be if it took
+                        // up no space, so it's large enough for the clusters left over after
+                        // making room for it. Any sectors at the end which don't make up a
+                        // whole cluster are unused.
+                        let fat_size_in_sectors =
+                            fat_size_in_sectors(available_num_sectors / sectors_per_cluster as u64);
+                        let num_data_clusters = available_num_sectors
+                            .saturating_sub(fat_size_in_sectors * NUM_FATS as u64)
+                            / sectors_per_cluster as u64;
+                        if num_data_clusters < num_used_data_clusters as u64 {
+                            return Err(FatError::PartitionTooSmall {
+                                num_clusters: num_data_clusters as u32,
+                                num_used_clusters: num_used_data_clusters,
+                            }
+                            .into());
+                        }
+                        if num_data_clusters < min_num_clusters as u64 {
+                            return Err(FatError::TooFewClusters {
+                                fat_type,
+                                num_clusters: num_data_clusters as u32,
+                            }
+                            .into());
+                        }
+                        if num_data_clusters > max_num_clusters as u64 {
+                            return Err(FatError::TooManyClusters {
+                                fat_type,
+                                num_clusters: num_data_clusters.min(u32::MAX as u64) as u32,
+                            }
+                            .into());
+                        }
+                        (num_data_clusters, fat_size_in_sectors, total_num_sectors)
+                    }
+                };
+            if total_num_sectors > u32::MAX as u64 {
+                return Err(FatError::TooManySectors(total_num_sectors).into());
+            }
+            Ok(Self {
+                fat_type,
+                bytes_per_sector,
+                sectors_per_cluster,
+                reserved_sector_count,
+                root_entry_count,
+                fat_size_in_sectors: fat_size_in_sectors as u32,
+                num_data_clusters: num_data_clusters as u32,
+                num_used_data_clusters,
+                total_num_sectors,
+            })
+        }
+
+        pub fn root_directory_sectors(&self) -> u32 {
+            self.root_entry_count as u32 * DIRECTORY_ENTRY_BYTES / self.bytes_per_sector as u32
+        }
+
+        /// Sectors at the end of the partition which aren't part of any cluster
+        pub fn num_unused_sectors(&self) -> u64 {
+            self.total_num_sectors
+                - (self.reserved_sector_count as u64
+                    + (self.fat_size_in_sectors as u64 * NUM_FATS as u64)
+                    + self.root_directory_sectors() as u64
+                    + (self.num_data_clusters as u64 * self.sectors_per_cluster as u64))
+        }
+
+        pub fn num_free_data_clusters(&self) -> u32 {
+            self.num_data_clusters - self.num_used_data_clusters
+        }
+
+        /// Clusters are allocated contiguously from the first cluster, so the free clusters
+        /// follow the used ones
+        pub fn first_free_cluster(&self) -> Option<u32> {
+            if self.num_free_data_clusters() == 0 {
+                None
+            } else {
+                Some(ROOT_CLUSTER + self.num_used_data_clusters)
+            }
+        }
+
+        pub fn bytes_per_cluster(&self) -> u32 {
+            self.bytes_per_sector as u32 * self.sectors_per_cluster as u32
+        }
+
+        pub fn total_num_bytes(&self) -> u64 {
+            self.total_num_sectors * self.bytes_per_sector as u64
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -42,6 +322,10 @@ pub enum FatError {
         fat_size_32: u32,
     },
     InvalidSignature(u16),
+    InvalidActiveFat {
+        active_fat: u8,
+        num_fats: u8,
+    },
     InvalidFatEntry(u32),
     FatLookup(FatLookupError),
     NoSuchFile,
@@ -49,6 +333,34 @@ pub enum FatError {
     InvalidDiskPath(String),
     ExpectedFileFoundDirectory,
     BpbDoesNotMatchBackupBpb,
+    FileTooLarge(u64),
+    ClusterChainTooShort,
+    CyclicClusterChain,
+    FileExists,
+    ExpectedDirectoryFoundFile,
+    DirectoryNotEmpty,
+    DirectoryMovedIntoItself,
+    NoFreeClusters,
+    RootDirectoryFull,
+    InvalidVolumeLabel(String),
+    InvalidOemName(String),
+    TooManyClusters {
+        fat_type: FatType,
+        num_clusters: u32,
+    },
+    TooManyRootDirectoryEntries(u32),
+    TooFewClusters {
+        fat_type: FatType,
+        num_clusters: u32,
+    },
+    TooManySectors(u64),
+    PartitionTooSmall {
+        num_clusters: u32,
+        num_used_clusters: u32,
+    },
+    InvalidBytesPerSector(u16),
+    InvalidSectorsPerCluster(u8),
+    InvalidSourceDateEpoch(String),
     InvalidFsInfoLeadSignature(u32),
     InvalidFsInfoStrucSignature(u32),
     InvalidFsInfoTrailSignature(u32),
@@ -95,6 +407,9 @@ struct Bpb {
 const BPB_SIZE: usize = 512;
 const REQUIRED_SIGNATURE: u16 = 0xAA55;
 const BOOT_SIGNATURE: u8 = 0x29;
+// On FAT32, the low bits of ext_flags select the only FAT in use when mirroring is disabled
+const EXT_FLAGS_ACTIVE_FAT_MASK: u16 = 0x000F;
+const EXT_FLAGS_MIRRORING_DISABLED: u16 = 0x0080;
 
 impl Bpb {
     fn read<H>(handle: &mut H, partition_byte_start: u64, buf: &mut Vec<u8>) -> Result<Self, Error>
@@ -102,15 +417,15 @@ impl Bpb {
         H: io::Seek + io::Read,
     {
         handle_read(handle, partition_byte_start, BPB_SIZE, buf)?;
-        let bpb = Bpb::parse(&buf)?;
+        let bpb = Bpb::parse(buf)?;
         if bpb.bk_boot_sector != 0 {
             handle_read(
                 handle,
-                partition_byte_start + (bpb.bytes_per_sector * bpb.bk_boot_sector) as u64,
+                partition_byte_start + (bpb.bytes_per_sector as u64 * bpb.bk_boot_sector as u64),
                 BPB_SIZE,
                 buf,
             )?;
-            let backup_bpb = Bpb::parse(&buf)?;
+            let backup_bpb = Bpb::parse(buf)?;
             if backup_bpb != bpb {
                 return Err(FatError::BpbDoesNotMatchBackupBpb.into());
             }
@@ -118,52 +433,65 @@ impl Bpb {
         Ok(bpb)
     }
 
-    fn new_fat32_raw(
-        hierarchy: &directory_hierarchy::DirectoryHierarchy,
-    ) -> Result<[u8; BPB_SIZE], Error> {
-        use std::time::{SystemTime, UNIX_EPOCH};
+    fn new_raw(
+        layout: &create::Layout,
+        oem_name: &[u8; OEM_NAME_SIZE],
+        volume_id: u32,
+        volume_label: &[u8; VOLUME_LABEL_SIZE],
+    ) -> [u8; BPB_SIZE] {
         let mut raw = [0; BPB_SIZE];
         raw[0..3].copy_from_slice(&create::JMP_BOOT);
-        let oem_bytes = create::OEM_NAME.as_bytes();
-        assert!(oem_bytes.len() <= 8);
-        raw[3..(3 + oem_bytes.len())].copy_from_slice(oem_bytes);
-        raw[11..13].copy_from_slice(&create::BYTES_PER_SECTOR.to_le_bytes());
-        raw[13] = create::SECTORS_PER_CLUSTER;
-        raw[14..16].copy_from_slice(&create::RESERVED_SECTOR_COUNT.to_le_bytes());
+        raw[3..11].copy_from_slice(oem_name);
+        raw[11..13].copy_from_slice(&layout.bytes_per_sector.to_le_bytes());
+        raw[13] = layout.sectors_per_cluster;
+        raw[14..16].copy_from_slice(&layout.reserved_sector_count.to_le_bytes());
         raw[16] = create::NUM_FATS;
-        raw[17..19].copy_from_slice(&0u16.to_le_bytes()); // root entry count - 0 on fat32
-        raw[19..21].copy_from_slice(&0u16.to_le_bytes()); // total sectors 16 - 0 on fat32
+        raw[17..19].copy_from_slice(&layout.root_entry_count.to_le_bytes()); // 0 on fat32
+        let num_sectors = layout.total_num_sectors; // number of sectors in all regions of the partition
+        let (total_sectors_16, total_sectors_32) =
+            if layout.fat_type != FatType::Fat32 && num_sectors <= u16::MAX as u64 {
+                (num_sectors as u16, 0)
+            } else {
+                (0, num_sectors as u32)
+            };
+        raw[19..21].copy_from_slice(&total_sectors_16.to_le_bytes());
         raw[21] = create::MEDIA_FIXED;
-        raw[22..24].copy_from_slice(&0u16.to_le_bytes()); // fat size 16 - 0 on fat32
         raw[24..26].copy_from_slice(&create::SECTORS_PER_TRACK.to_le_bytes());
         raw[26..28].copy_from_slice(&create::NUM_HEADS.to_le_bytes());
         raw[28..32].copy_from_slice(&create::NUM_HIDDEN_SECTORS.to_le_bytes());
-        let num_sectors = hierarchy.implied_total_num_clusters()? as u32; // number of sectors in all regions of the partition
-        raw[32..36].copy_from_slice(&num_sectors.to_le_bytes());
-        let fat_size_in_bytes: u32 =
-            hierarchy.implied_num_data_clusters()? as u32 * create::FAT_ENTRY_SIZE_BYTES as u32;
-        let fat_size_in_sectors: u32 =
-            ((fat_size_in_bytes - 1) / create::BYTES_PER_SECTOR as u32) + 1; // divide rounding up
-        raw[36..40].copy_from_slice(&fat_size_in_sectors.to_le_bytes());
-        raw[40..42].copy_from_slice(&0u16.to_le_bytes()); // flags
-        raw[42..44].copy_from_slice(&create::VERSION.to_le_bytes());
-        raw[44..48].copy_from_slice(&create::ROOT_CLUSTER.to_le_bytes());
-        raw[48..50].copy_from_slice(&create::FS_INFO.to_le_bytes());
-        raw[50..52].copy_from_slice(&create::BK_BOOT_SECTOR.to_le_bytes());
-        raw[64] = create::DRIVE_NUM;
-        raw[66] = BOOT_SIGNATURE;
-        let since_epoch = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_else(|e| e.duration())
-            .as_millis() as u32;
-        raw[67..71].copy_from_slice(&since_epoch.to_le_bytes());
-        let volume_label_bytes = create::VOLUME_LABEL.as_bytes();
-        assert!(volume_label_bytes.len() <= 11);
-        raw[71..(71 + volume_label_bytes.len())].copy_from_slice(volume_label_bytes);
-        let file_system_type = create::FAT32_FILE_SYSTEM_TYPE.as_bytes();
-        raw[82..(82 + file_system_type.len())].copy_from_slice(file_system_type);
+        raw[32..36].copy_from_slice(&total_sectors_32.to_le_bytes());
+        // The fields following the common fields differ between FAT32 and FAT12/FAT16. Both end
+        // with the same fields starting from the drive number, at different offsets.
+        let (drive_number_offset, file_system_type) = match layout.fat_type {
+            FatType::Fat12 | FatType::Fat16 => {
+                let fat_size_16 = layout.fat_size_in_sectors as u16;
+                raw[22..24].copy_from_slice(&fat_size_16.to_le_bytes());
+                let file_system_type = if layout.fat_type == FatType::Fat12 {
+                    create::FAT12_FILE_SYSTEM_TYPE
+                } else {
+                    create::FAT16_FILE_SYSTEM_TYPE
+                };
+                (36, file_system_type)
+            }
+            FatType::Fat32 => {
+                raw[22..24].copy_from_slice(&0u16.to_le_bytes()); // fat size 16 - 0 on fat32
+                raw[36..40].copy_from_slice(&layout.fat_size_in_sectors.to_le_bytes());
+                raw[40..42].copy_from_slice(&0u16.to_le_bytes()); // flags
+                raw[42..44].copy_from_slice(&create::VERSION.to_le_bytes());
+                raw[44..48].copy_from_slice(&create::ROOT_CLUSTER.to_le_bytes());
+                raw[48..50].copy_from_slice(&create::FS_INFO.to_le_bytes());
+                raw[50..52].copy_from_slice(&create::BK_BOOT_SECTOR.to_le_bytes());
+                (64, create::FAT32_FILE_SYSTEM_TYPE)
+            }
+        };
+        let raw_tail = &mut raw[drive_number_offset..];
+        raw_tail[0] = create::DRIVE_NUM;
+        raw_tail[2] = BOOT_SIGNATURE;
+        raw_tail[3..7].copy_from_slice(&volume_id.to_le_bytes());
+        raw_tail[7..18].copy_from_slice(volume_label);
+        raw_tail[18..26].copy_from_slice(file_system_type.as_bytes());
         raw[510..512].copy_from_slice(&REQUIRED_SIGNATURE.to_le_bytes());
-        Ok(raw)
+        raw
     }
 
     fn parse(raw: &[u8]) -> Result<Self, Error> {
@@ -195,7 +523,16 @@ impl Bpb {
             volume_label,
             file_system_type,
         );
-        if (total_sectors_16 == 0) && (total_sectors_32 != 0) {
+        if (total_sectors_16 == 0) == (total_sectors_32 == 0) {
+            return Err(FatError::ExactlyOneTotalSectorsFieldMustBeZero {
+                total_sectors_16,
+                total_sectors_32,
+            }
+            .into());
+        }
+        // FAT12 and FAT16 volumes with too many sectors for total_sectors_16 use
+        // total_sectors_32, so the FAT size field is used to tell them apart from FAT32
+        if fat_size_16 == 0 {
             // FAT32
             fat_size_32 = u32::from_le_bytes(raw[36..40].try_into().unwrap());
             ext_flags = u16::from_le_bytes(raw[40..42].try_into().unwrap());
@@ -203,10 +540,8 @@ impl Bpb {
             root_cluster = u32::from_le_bytes(raw[44..48].try_into().unwrap());
             fs_info = u16::from_le_bytes(raw[48..50].try_into().unwrap());
             bk_boot_sector = u16::from_le_bytes(raw[50..52].try_into().unwrap());
-            for i in 52..64 {
-                if raw[i] != 0 {
-                    return Err(FatError::UnexpectedNonZero { byte_index: i }.into());
-                }
+            if let Some(i) = (52..64).find(|&i| raw[i] != 0) {
+                return Err(FatError::UnexpectedNonZero { byte_index: i }.into());
             }
             drive_number = raw[64];
             if raw[65] != 0 {
@@ -217,7 +552,7 @@ impl Bpb {
             volume_id = u32::from_le_bytes(raw[67..71].try_into().unwrap());
             volume_label = String::from_utf8_lossy(&raw[71..82]).to_string();
             file_system_type = String::from_utf8_lossy(&raw[82..90]).to_string();
-        } else if (total_sectors_16 != 0) && (total_sectors_32 == 0) {
+        } else {
             // FAT12 or FAT16
             fat_size_32 = 0;
             ext_flags = 0;
@@ -233,12 +568,6 @@ impl Bpb {
             volume_id = u32::from_le_bytes(raw[39..43].try_into().unwrap());
             volume_label = String::from_utf8_lossy(&raw[43..54]).to_string();
             file_system_type = String::from_utf8_lossy(&raw[54..62]).to_string();
-        } else {
-            return Err(FatError::ExactlyOneTotalSectorsFieldMustBeZero {
-                total_sectors_16,
-                total_sectors_32,
-            }
-            .into());
         }
         if (fat_size_16 == 0) == (fat_size_32 == 0) {
             return Err(FatError::ExactlyOneFatSizeMustBeZero {
@@ -247,6 +576,16 @@ impl Bpb {
             }
             .into());
         }
+        if ext_flags & EXT_FLAGS_MIRRORING_DISABLED != 0 {
+            let active_fat = (ext_flags & EXT_FLAGS_ACTIVE_FAT_MASK) as u8;
+            if active_fat >= num_fats {
+                return Err(FatError::InvalidActiveFat {
+                    active_fat,
+                    num_fats,
+                }
+                .into());
+            }
+        }
         let signature = u16::from_le_bytes(raw[510..512].try_into().unwrap());
         if signature != REQUIRED_SIGNATURE {
             return Err(FatError::InvalidSignature(signature).into());
@@ -291,9 +630,8 @@ impl Bpb {
     }
 
     fn count_of_clusters(&self) -> u32 {
-        let root_dir_sectors = ((self.root_entry_count as u32 * 32)
-            + (self.bytes_per_sector as u32 - 1))
-            / self.bytes_per_sector as u32;
+        let root_dir_sectors =
+            (self.root_entry_count as u32 * 32).div_ceil(self.bytes_per_sector as u32);
         let total_sectors = if self.total_sectors_16 != 0 {
             self.total_sectors_16 as u32
         } else {
@@ -331,8 +669,39 @@ impl Bpb {
             * self.bytes_per_sector as u64
     }
 
+    /// Whether only the active FAT is kept up to date, rather than every copy
+    fn mirroring_disabled(&self) -> bool {
+        self.ext_flags & EXT_FLAGS_MIRRORING_DISABLED != 0
+    }
+
+    /// The FAT which is read. When mirroring is enabled all copies are equivalent, and the first
+    /// is used.
+    fn active_fat(&self) -> u8 {
+        if self.mirroring_disabled() {
+            (self.ext_flags & EXT_FLAGS_ACTIVE_FAT_MASK) as u8
+        } else {
+            0
+        }
+    }
+
+    /// The FATs which must be updated when the FAT is modified
+    fn fats_to_update(&self) -> Range<u8> {
+        if self.mirroring_disabled() {
+            self.active_fat()..(self.active_fat() + 1)
+        } else {
+            0..self.num_fats
+        }
+    }
+
+    /// The offset of the `i`th copy of the FAT
+    fn fat_copy_offset(&self, i: u8) -> u64 {
+        (self.reserved_sector_count as u64 + i as u64 * self.fat_size_in_sectors() as u64)
+            * self.bytes_per_sector as u64
+    }
+
+    /// The offset of the active FAT
     fn fat_offset(&self) -> u64 {
-        self.reserved_sector_count as u64 * self.bytes_per_sector as u64
+        self.fat_copy_offset(self.active_fat())
     }
 
     fn data_offset(&self) -> u64 {
@@ -362,6 +731,23 @@ impl FatType {
             Self::Fat32 => 0x0FFFFFF7,
         }
     }
+
+    fn fat_entry_end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0xFFF,
+            Self::Fat16 => 0xFFFF,
+            Self::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+    fn fat_size_in_bytes(self, num_entries: u32) -> u64 {
+        let num_entries = num_entries as u64;
+        match self {
+            Self::Fat12 => (num_entries * 3).div_ceil(2),
+            Self::Fat16 => num_entries * 2,
+            Self::Fat32 => num_entries * 4,
+        }
+    }
 }
 
 mod directory_attributes {
@@ -370,10 +756,50 @@ mod directory_attributes {
     pub const SYSTEM: u8 = 0x04;
     pub const VOLUME_ID: u8 = 0x08;
     pub const DIRECTORY: u8 = 0x10;
+    pub const ARCHIVE: u8 = 0x20;
 
     pub const LONG_NAME: u8 = READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID;
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes {
+    pub read_only: bool,
+    pub hidden: bool,
+    pub system: bool,
+    pub volume_label: bool,
+    pub directory: bool,
+    pub archive: bool,
+}
+
+impl Attributes {
+    pub fn from_raw(raw: u8) -> Self {
+        use directory_attributes::*;
+        Self {
+            read_only: raw & READ_ONLY != 0,
+            hidden: raw & HIDDEN != 0,
+            system: raw & SYSTEM != 0,
+            volume_label: raw & VOLUME_ID != 0,
+            directory: raw & DIRECTORY != 0,
+            archive: raw & ARCHIVE != 0,
+        }
+    }
+
+    pub fn raw(&self) -> u8 {
+        use directory_attributes::*;
+        [
+            (self.read_only, READ_ONLY),
+            (self.hidden, HIDDEN),
+            (self.system, SYSTEM),
+            (self.volume_label, VOLUME_ID),
+            (self.directory, DIRECTORY),
+            (self.archive, ARCHIVE),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |raw, (_, flag)| raw | flag)
+    }
+}
+
 #[derive(Debug)]
 struct RawDirectoryEntryNormal {
     short_filename_main: String,
@@ -393,16 +819,105 @@ struct RawDirectoryEntryNormal {
 struct RawDirectoryEntryLongName {
     order: u8,
     name: String,
-    attributes: u8,
     checksum: u8,
 }
 
 #[derive(Debug)]
 enum RawDirectoryEntry {
-    Normal(RawDirectoryEntryNormal),
+    Normal {
+        entry: RawDirectoryEntryNormal,
+        // computed from the raw name bytes, which can't always be recovered from the decoded name
+        short_name_checksum: u8,
+    },
     LongName(RawDirectoryEntryLongName),
 }
 
+fn short_name_checksum(short_name_raw: &[u8]) -> u8 {
+    short_name_raw
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+impl RawDirectoryEntryNormal {
+    fn short_name_raw(&self) -> [u8; 11] {
+        const PAD_SPACE: u8 = 0x20;
+        let main = self.short_filename_main.as_bytes();
+        let extension = self.short_filename_extension.as_bytes();
+        assert!(main.len() <= 8 && extension.len() <= 3);
+        let mut raw = [PAD_SPACE; 11];
+        raw[0..main.len()].copy_from_slice(main);
+        raw[8..(8 + extension.len())].copy_from_slice(extension);
+        raw
+    }
+
+    fn short_name_checksum(&self) -> u8 {
+        short_name_checksum(&self.short_name_raw())
+    }
+
+    fn encode(&self) -> [u8; DIRECTORY_ENTRY_BYTES as usize] {
+        let mut raw = [0; DIRECTORY_ENTRY_BYTES as usize];
+        raw[0..11].copy_from_slice(&self.short_name_raw());
+        raw[11] = self.attributes;
+        raw[13] = self.creation_time_tenth;
+        raw[14..16].copy_from_slice(&self.creation_time.to_le_bytes());
+        raw[16..18].copy_from_slice(&self.creation_date.to_le_bytes());
+        raw[18..20].copy_from_slice(&self.last_accessed_date.to_le_bytes());
+        raw[20..22].copy_from_slice(&((self.first_cluster >> 16) as u16).to_le_bytes());
+        raw[22..24].copy_from_slice(&self.write_time.to_le_bytes());
+        raw[24..26].copy_from_slice(&self.write_date.to_le_bytes());
+        raw[26..28].copy_from_slice(&(self.first_cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&self.file_size.to_le_bytes());
+        raw
+    }
+}
+
+impl RawDirectoryEntryLongName {
+    fn ordinal(&self) -> u8 {
+        self.order & !LAST_LONG_DIRECTORY_ENTRY_FLAG
+    }
+
+    fn is_last(&self) -> bool {
+        self.order & LAST_LONG_DIRECTORY_ENTRY_FLAG != 0
+    }
+
+    /// Returns the long name entries storing `name`, in the order they appear on disk (ie. the
+    /// entry containing the end of the name comes first).
+    fn encode_name(name: &str, checksum: u8) -> Vec<[u8; DIRECTORY_ENTRY_BYTES as usize]> {
+        const NAME_PAD: u16 = 0xFFFF;
+        let name_utf16 = name.encode_utf16().collect::<Vec<_>>();
+        let chunks = name_utf16.chunks(NUM_CHARS_PER_LONG_DIRECTORY_ENTRY as usize);
+        let num_entries = chunks.len();
+        chunks
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut chars = [NAME_PAD; NUM_CHARS_PER_LONG_DIRECTORY_ENTRY as usize];
+                chars[0..chunk.len()].copy_from_slice(chunk);
+                if chunk.len() < chars.len() {
+                    // names that don't fill the final entry are null-terminated
+                    chars[chunk.len()] = 0;
+                }
+                let mut order = i as u8 + 1;
+                if i + 1 == num_entries {
+                    order |= LAST_LONG_DIRECTORY_ENTRY_FLAG;
+                }
+                let mut raw = [0; DIRECTORY_ENTRY_BYTES as usize];
+                raw[0] = order;
+                let char_offsets = (1..11)
+                    .step_by(2)
+                    .chain((14..26).step_by(2))
+                    .chain((28..32).step_by(2));
+                for (c, offset) in chars.iter().zip(char_offsets) {
+                    raw[offset..(offset + 2)].copy_from_slice(&c.to_le_bytes());
+                }
+                raw[11] = directory_attributes::LONG_NAME;
+                raw[13] = checksum;
+                raw
+            })
+            .rev()
+            .collect()
+    }
+}
+
 impl RawDirectoryEntry {
     fn parse(raw: &[u8]) -> Result<Self, Error> {
         use std::convert::TryInto;
@@ -428,7 +943,6 @@ impl RawDirectoryEntry {
             Self::LongName(RawDirectoryEntryLongName {
                 order,
                 name,
-                attributes,
                 checksum,
             })
         } else {
@@ -436,7 +950,7 @@ impl RawDirectoryEntry {
                 const SKIP_SPACE: u8 = 0x20;
                 String::from_utf8_lossy(
                     &slice
-                        .into_iter()
+                        .iter()
                         .cloned()
                         .skip_while(|&c| c == SKIP_SPACE)
                         .take_while(|&c| c != SKIP_SPACE)
@@ -459,19 +973,22 @@ impl RawDirectoryEntry {
             let first_cluster_lo = u16::from_le_bytes(raw[26..28].try_into().unwrap());
             let first_cluster = ((first_cluster_hi as u32) << 16) | (first_cluster_lo as u32);
             let file_size = u32::from_le_bytes(raw[28..32].try_into().unwrap());
-            Self::Normal(RawDirectoryEntryNormal {
-                short_filename_main,
-                short_filename_extension,
-                attributes,
-                creation_time_tenth,
-                creation_time,
-                creation_date,
-                last_accessed_date,
-                first_cluster,
-                write_time,
-                write_date,
-                file_size,
-            })
+            Self::Normal {
+                entry: RawDirectoryEntryNormal {
+                    short_filename_main,
+                    short_filename_extension,
+                    attributes,
+                    creation_time_tenth,
+                    creation_time,
+                    creation_date,
+                    last_accessed_date,
+                    first_cluster,
+                    write_time,
+                    write_date,
+                    file_size,
+                },
+                short_name_checksum: short_name_checksum(&raw[0..11]),
+            }
         };
         Ok(entry)
     }
@@ -484,9 +1001,39 @@ pub struct DirectoryEntry {
     file_size: u32,
     first_cluster: u32,
     attributes: u8,
+    creation: Option<FatDateTime>,
+    modification: Option<FatDateTime>,
+    last_accessed: Option<FatDate>,
+    // indices of the raw entries making up this entry, including any long name entries
+    slots: Range<usize>,
+}
+
+/// How names in paths are compared with the names of directory entries. Both modes accept either
+/// an entry's long name or its short name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameMatch {
+    #[default]
+    CaseInsensitive,
+    Exact,
+}
+
+fn eq_ignoring_case(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_uppercase)
+        .eq(b.chars().flat_map(char::to_uppercase))
 }
 
 impl DirectoryEntry {
+    fn matches_name(&self, name: &str, name_match: NameMatch) -> bool {
+        let mut names = self.long_name.iter().chain(Some(&self.short_name));
+        match name_match {
+            NameMatch::CaseInsensitive => {
+                names.any(|entry_name| eq_ignoring_case(entry_name, name))
+            }
+            NameMatch::Exact => names.any(|entry_name| entry_name == name),
+        }
+    }
+
     pub fn name(&self) -> &str {
         self.long_name
             .as_ref()
@@ -495,71 +1042,193 @@ impl DirectoryEntry {
     pub fn is_directory(&self) -> bool {
         self.attributes & directory_attributes::DIRECTORY != 0
     }
+    pub fn short_name(&self) -> &str {
+        self.short_name.as_str()
+    }
+    pub fn long_name(&self) -> Option<&str> {
+        self.long_name.as_deref()
+    }
+    pub fn file_size(&self) -> u32 {
+        self.file_size
+    }
+    pub fn first_cluster(&self) -> u32 {
+        self.first_cluster
+    }
+    pub fn attributes(&self) -> Attributes {
+        Attributes::from_raw(self.attributes)
+    }
+    /// Timestamps are `None` if they are unset (zero) or invalid
+    pub fn creation(&self) -> Option<FatDateTime> {
+        self.creation
+    }
+    pub fn modification(&self) -> Option<FatDateTime> {
+        self.modification
+    }
+    /// Only the date of the last access is recorded
+    pub fn last_accessed(&self) -> Option<FatDate> {
+        self.last_accessed
+    }
 }
 
 pub struct Directory {
     entries: Vec<DirectoryEntry>,
+    diagnostics: Vec<DirectoryDiagnostic>,
+}
+
+/// Problems with long name entries found while decoding a directory. Affected entries are
+/// given their short name instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectoryDiagnostic {
+    /// Long name entries which don't form part of a valid sequence, such as those left behind
+    /// when a file is deleted by an implementation which doesn't support long names
+    OrphanedLongNameEntries { num_entries: usize },
+    /// A short entry is preceded by a sequence of long name entries which is missing entries
+    IncompleteLongName { short_name: String },
+    /// A short entry is preceded by long name entries whose checksum doesn't match its short name
+    LongNameChecksumMismatch { short_name: String },
 }
 
 const DIRECTORY_ENTRY_BYTES: u32 = 32;
 const NUM_CHARS_PER_LONG_DIRECTORY_ENTRY: u32 = 13;
+const LAST_LONG_DIRECTORY_ENTRY_FLAG: u8 = 0x40;
 const UNUSED_ENTRY_PREFIX: u8 = 0xE5;
 const END_OF_DIRECTORY_PREFIX: u8 = 0;
 
+/// Long name entries seen since the most recent short entry. Long name entries are stored in
+/// reverse order, so a sequence starts with the entry with the last-entry flag and the highest
+/// ordinal, and ends with ordinal 1 immediately before the short entry it names.
+#[derive(Default)]
+struct LongNameSequence {
+    entries: Vec<RawDirectoryEntryLongName>,
+}
+
+impl LongNameSequence {
+    /// Returns the number of entries which were discarded because they can't be part of a valid
+    /// sequence
+    fn push(&mut self, entry: RawDirectoryEntryLongName) -> usize {
+        let continues_sequence = self.entries.last().is_some_and(|previous| {
+            !entry.is_last()
+                && entry.ordinal() + 1 == previous.ordinal()
+                && entry.checksum == previous.checksum
+        });
+        if continues_sequence {
+            self.entries.push(entry);
+            return 0;
+        }
+        let num_discarded = self.entries.len();
+        self.entries.clear();
+        if entry.is_last() && entry.ordinal() != 0 {
+            self.entries.push(entry);
+            num_discarded
+        } else {
+            num_discarded + 1
+        }
+    }
+
+    /// Discards the entries of a sequence which isn't followed by a short name entry, returning
+    /// the number of entries discarded
+    fn discard(&mut self) -> usize {
+        let num_discarded = self.entries.len();
+        self.entries.clear();
+        num_discarded
+    }
+
+    fn take_long_name(
+        &mut self,
+        short_name: &str,
+        short_name_checksum: u8,
+    ) -> Result<Option<String>, DirectoryDiagnostic> {
+        let entries = std::mem::take(&mut self.entries);
+        let last = match entries.last() {
+            None => return Ok(None),
+            Some(last) => last,
+        };
+        if last.ordinal() != 1 {
+            return Err(DirectoryDiagnostic::IncompleteLongName {
+                short_name: short_name.to_string(),
+            });
+        }
+        if last.checksum != short_name_checksum {
+            return Err(DirectoryDiagnostic::LongNameChecksumMismatch {
+                short_name: short_name.to_string(),
+            });
+        }
+        Ok(Some(
+            entries
+                .iter()
+                .rev()
+                .map(|entry| entry.name.as_str())
+                .collect(),
+        ))
+    }
+}
+
 impl Directory {
     fn from_traverser<H>(traverser: &mut Traverser<H>, cluster_index: u32) -> Result<Self, Error>
     where
         H: io::Seek + io::Read,
     {
-        let mut entries = Vec::new();
-        let mut error = None;
+        // Long name sequences may span cluster boundaries, so the directory is decoded in one go
+        let mut raw = Vec::new();
         traverser
             .traverse(cluster_index)
             .for_each::<_, ()>(|cluster_data| {
-                Self::raw_entries_to_entries(Self::raw_from_contiguous(cluster_data)).for_each(
-                    |entry_result| match entry_result {
-                        Ok(entry) => entries.push(entry),
-                        Err(e) => error = Some(e),
-                    },
-                );
+                raw.extend_from_slice(cluster_data);
                 None
             })?;
-        if let Some(e) = error {
-            Err(e)
-        } else {
-            Ok(Self { entries })
-        }
+        Self::from_contiguous(&raw)
     }
 
     fn raw_from_contiguous<'a>(
         raw: &'a [u8],
-    ) -> impl 'a + Iterator<Item = Result<RawDirectoryEntry, Error>> {
+    ) -> impl 'a + Iterator<Item = Result<(usize, RawDirectoryEntry), Error>> {
         raw.chunks(DIRECTORY_ENTRY_BYTES as usize)
-            .take_while(|raw_entry| raw_entry[0] != END_OF_DIRECTORY_PREFIX)
-            .filter(|raw_entry| raw_entry[0] != UNUSED_ENTRY_PREFIX)
-            .map(RawDirectoryEntry::parse)
+            .enumerate()
+            .take_while(|(_, raw_entry)| raw_entry[0] != END_OF_DIRECTORY_PREFIX)
+            .filter(|(_, raw_entry)| raw_entry[0] != UNUSED_ENTRY_PREFIX)
+            .map(|(index, raw_entry)| Ok((index, RawDirectoryEntry::parse(raw_entry)?)))
     }
 
-    fn raw_entries_to_entries<I>(iter: I) -> impl Iterator<Item = Result<DirectoryEntry, Error>>
+    fn from_raw_entries<I>(iter: I) -> Result<Self, Error>
     where
-        I: IntoIterator<Item = Result<RawDirectoryEntry, Error>>,
+        I: IntoIterator<Item = Result<(usize, RawDirectoryEntry), Error>>,
     {
-        let mut name_parts = Vec::new();
-        iter.into_iter()
-            .filter_map(move |raw_entry_result| match raw_entry_result {
-                Err(e) => Some(Err(e)),
-                Ok(RawDirectoryEntry::LongName(long_name_entry)) => {
-                    name_parts.push(long_name_entry.name);
-                    None
+        let mut entries = Vec::new();
+        let mut diagnostics = Vec::new();
+        let mut record = |diagnostic: DirectoryDiagnostic| {
+            log::warn!("{:?}", diagnostic);
+            diagnostics.push(diagnostic);
+        };
+        let mut long_name_sequence = LongNameSequence::default();
+        let mut long_name_start = 0;
+        for raw_entry_result in iter {
+            let (index, raw_entry) = raw_entry_result?;
+            match raw_entry {
+                RawDirectoryEntry::LongName(long_name_entry) => {
+                    let num_entries = long_name_sequence.push(long_name_entry);
+                    if num_entries > 0 {
+                        record(DirectoryDiagnostic::OrphanedLongNameEntries { num_entries });
+                    }
+                    if long_name_sequence.entries.len() == 1 {
+                        long_name_start = index;
+                    }
                 }
-                Ok(RawDirectoryEntry::Normal(normal_entry)) => {
-                    let long_name = if name_parts.is_empty() {
-                        None
-                    } else {
-                        let mut long_names = String::new();
-                        long_names.extend(name_parts.drain(..).rev());
-                        Some(long_names)
-                    };
+                RawDirectoryEntry::Normal {
+                    entry: normal_entry,
+                    short_name_checksum: _,
+                } if normal_entry.attributes & directory_attributes::VOLUME_ID != 0 => {
+                    // the volume label entry in the root directory isn't a file or directory, and
+                    // doesn't have a long name
+                    let num_entries = long_name_sequence.discard();
+                    if num_entries > 0 {
+                        record(DirectoryDiagnostic::OrphanedLongNameEntries { num_entries });
+                    }
+                    continue;
+                }
+                RawDirectoryEntry::Normal {
+                    entry: normal_entry,
+                    short_name_checksum,
+                } => {
                     let short_name = if normal_entry.short_filename_extension.is_empty() {
                         normal_entry.short_filename_main
                     } else {
@@ -568,31 +1237,74 @@ impl Directory {
                             normal_entry.short_filename_main, normal_entry.short_filename_extension
                         )
                     };
-                    Some(Ok(DirectoryEntry {
+                    let long_name = long_name_sequence
+                        .take_long_name(&short_name, short_name_checksum)
+                        .unwrap_or_else(|diagnostic| {
+                            record(diagnostic);
+                            None
+                        });
+                    let slots = if long_name.is_some() {
+                        long_name_start..(index + 1)
+                    } else {
+                        index..(index + 1)
+                    };
+                    entries.push(DirectoryEntry {
                         short_name,
                         long_name,
                         file_size: normal_entry.file_size,
                         first_cluster: normal_entry.first_cluster,
                         attributes: normal_entry.attributes,
-                    }))
+                        creation: FatDateTime::from_raw(
+                            normal_entry.creation_date,
+                            normal_entry.creation_time,
+                            normal_entry.creation_time_tenth,
+                        ),
+                        modification: FatDateTime::from_raw(
+                            normal_entry.write_date,
+                            normal_entry.write_time,
+                            0,
+                        ),
+                        last_accessed: FatDate::from_raw(normal_entry.last_accessed_date),
+                        slots,
+                    });
                 }
-            })
+            }
+        }
+        let num_entries = long_name_sequence.discard();
+        if num_entries > 0 {
+            record(DirectoryDiagnostic::OrphanedLongNameEntries { num_entries });
+        }
+        Ok(Self {
+            entries,
+            diagnostics,
+        })
     }
 
     fn from_contiguous(raw: &[u8]) -> Result<Self, Error> {
-        let mut entries = Vec::new();
-        for entry_result in Self::raw_entries_to_entries(Self::raw_from_contiguous(raw)) {
-            entries.push(entry_result?);
-        }
-        Ok(Self { entries })
+        Self::from_raw_entries(Self::raw_from_contiguous(raw))
     }
 
     pub fn entries(&self) -> &[DirectoryEntry] {
         &self.entries
     }
 
+    pub fn diagnostics(&self) -> &[DirectoryDiagnostic] {
+        &self.diagnostics
+    }
+
+    /// Finds an entry by its long or short name, ignoring case as FAT implementations do
     pub fn find_entry(&self, name: &str) -> Option<&DirectoryEntry> {
-        self.entries.iter().find(|entry| entry.name() == name)
+        self.find_entry_with_name_match(name, NameMatch::CaseInsensitive)
+    }
+
+    pub fn find_entry_with_name_match(
+        &self,
+        name: &str,
+        name_match: NameMatch,
+    ) -> Option<&DirectoryEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.matches_name(name, name_match))
     }
 }
 
@@ -600,6 +1312,8 @@ const FS_INFO_SIZE: usize = 512;
 const FS_INFO_REQUIRED_LEAD_SIGNATURE: u32 = 0x41615252;
 const FS_INFO_REQUIRED_STRUC_SIGNATURE: u32 = 0x61417272;
 const FS_INFO_REQUIRED_TRAIL_SIGNATURE: u32 = 0xAA550000;
+const FS_INFO_FREE_COUNT_UNKNOWN: u32 = 0xFFFFFFFF;
+const FS_INFO_NEXT_FREE_UNKNOWN: u32 = 0xFFFFFFFF;
 
 #[derive(Debug, PartialEq, Eq)]
 struct FsInfo {
@@ -610,9 +1324,14 @@ struct FsInfo {
     trail_signature: u32,
 }
 
-#[derive(Debug)]
-enum FsInfoWarning {
-    BackupDoesNotMatch(Box<FsInfo>),
+/// Problems with the backup FsInfo structure, which don't prevent the volume from being read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsInfoWarning {
+    /// The backup's hints differ from those of the primary FsInfo structure
+    BackupDoesNotMatch {
+        free_count: u32,
+        next_free: u32,
+    },
     NoBackup,
 }
 
@@ -654,7 +1373,10 @@ impl FsInfo {
         let warning = match Self::parse(buf) {
             Ok(fs_info_backup) => {
                 if fs_info_backup != fs_info {
-                    Some(FsInfoWarning::BackupDoesNotMatch(Box::new(fs_info_backup)))
+                    Some(FsInfoWarning::BackupDoesNotMatch {
+                        free_count: fs_info_backup.free_count,
+                        next_free: fs_info_backup.next_free,
+                    })
                 } else {
                     None
                 }
@@ -749,34 +1471,12 @@ where
 {
     // The first 2 entries of the FAT are unused
     debug_assert!(n >= 2);
-    match fat_type {
-        FatType::Fat32 => {
-            let base = n as u64 * 4;
-            handle.seek(io::SeekFrom::Start(fat_start + base))?;
-            let mut buf = [0; 4];
-            handle.read_exact(&mut buf)?;
-            Ok(u32::from_le_bytes(buf) & 0x0FFFFFFF)
-        }
-        FatType::Fat16 => {
-            let base = n as u64 * 2;
-            handle.seek(io::SeekFrom::Start(fat_start + base))?;
-            let mut buf = [0; 2];
-            handle.read_exact(&mut buf)?;
-            Ok(u16::from_le_bytes(buf) as u32)
-        }
-        FatType::Fat12 => {
-            let base = n as u64 + (n as u64 / 2);
-            handle.seek(io::SeekFrom::Start(fat_start + base))?;
-            let mut buf = [0; 2];
-            handle.read_exact(&mut buf)?;
-            let entry16 = u16::from_le_bytes(buf);
-            if n & 1 == 0 {
-                Ok((entry16 & 0xFFF) as u32)
-            } else {
-                Ok((entry16 >> 4) as u32)
-            }
-        }
-    }
+    let entry_bytes = fat_entry_bytes(fat_type, n);
+    let mut buf = [0; 4];
+    let buf = &mut buf[0..entry_bytes.len()];
+    handle.seek(io::SeekFrom::Start(fat_start + entry_bytes.start as u64))?;
+    handle.read_exact(buf)?;
+    Ok(fat_entry_from_bytes(buf, fat_type, n))
 }
 
 struct Traverser<'a, H>
@@ -787,6 +1487,7 @@ where
     handle: &'a mut H,
     partition_byte_start: u64,
     bpb: Bpb,
+    name_match: NameMatch,
 }
 
 impl<'a, H> Traverser<'a, H>
@@ -801,6 +1502,7 @@ where
             handle,
             partition_byte_start,
             bpb,
+            name_match: NameMatch::default(),
         })
     }
 
@@ -811,6 +1513,37 @@ where
         }
     }
 
+    fn cluster_byte_offset(&self, cluster: u32) -> u64 {
+        // Subtract 2 because the first cluster is cluster number 2
+        self.partition_byte_start
+            + self.bpb.data_offset()
+            + (cluster as u64 - 2) * self.bpb.bytes_per_cluster() as u64
+    }
+
+    /// Checks that a cluster taken from a directory entry or the FAT refers to a data cluster
+    fn allocated_cluster(&self, cluster: u32) -> Result<u32, Error> {
+        match classify_fat_entry(
+            self.bpb.fat_type(),
+            cluster,
+            self.bpb.maximum_valid_cluster(),
+        )
+        .map_err(FatError::FatLookup)?
+        {
+            FileFatEntry::AllocatedCluster(cluster) => Ok(cluster),
+            FileFatEntry::EndOfFile => Err(FatError::ClusterChainTooShort.into()),
+        }
+    }
+
+    fn next_allocated_cluster(&mut self, cluster: u32) -> Result<u32, Error> {
+        let next = fat_entry_of_nth_cluster(
+            self.handle,
+            self.bpb.fat_type(),
+            self.partition_byte_start + self.bpb.fat_offset(),
+            cluster,
+        )?;
+        self.allocated_cluster(next)
+    }
+
     fn read_root_directory(&mut self) -> Result<Directory, Error>
     where
         H: io::Seek + io::Read,
@@ -824,7 +1557,7 @@ where
                     self.bpb.root_directory_size(),
                     &mut self.buf,
                 )?;
-                let directory = Directory::from_contiguous(&mut self.buf)?;
+                let directory = Directory::from_contiguous(&self.buf)?;
                 Ok(directory)
             }
         }
@@ -858,9 +1591,10 @@ where
                         FatFile::Normal(_) => return Err(FatError::InvalidPath.into()),
                         FatFile::Directory(ref directory) => directory,
                     };
-                    let lookup_path = if let Some(entry) =
-                        directory.find_entry(os_str.to_string_lossy().to_string().as_str())
-                    {
+                    let lookup_path = if let Some(entry) = directory.find_entry_with_name_match(
+                        os_str.to_string_lossy().to_string().as_str(),
+                        self.name_match,
+                    ) {
                         if entry.is_directory() {
                             FatFile::Directory(Directory::from_traverser(
                                 self,
@@ -907,10 +1641,7 @@ where
                 FileFatEntry::EndOfFile => break Ok(None),
                 FileFatEntry::AllocatedCluster(entry) => entry,
             };
-            // Subtract 2 because the first cluster is cluster number 2
-            let cluster_start = self.traverser.partition_byte_start
-                + self.traverser.bpb.data_offset()
-                + (entry as u64 - 2) * self.traverser.bpb.bytes_per_cluster() as u64;
+            let cluster_start = self.traverser.cluster_byte_offset(entry);
             handle_read(
                 self.traverser.handle,
                 cluster_start,
@@ -934,6 +1665,10 @@ where
     where
         O: io::Write,
     {
+        if size == 0 {
+            // empty files have no clusters
+            return Ok(());
+        }
         let mut remaining = size as usize;
         let maybe_maybe_io_error = self.for_each(|data| {
             if let Some(next_remaining) = remaining.checked_sub(data.len()) {
@@ -968,6 +1703,12 @@ where
         Traverser::new(handle, partition_byte_range.start).map(Self)
     }
 
+    /// Paths are matched case-insensitively by default
+    pub fn with_name_match(mut self, name_match: NameMatch) -> Self {
+        self.0.name_match = name_match;
+        self
+    }
+
     pub fn lookup<P>(&mut self, path: P) -> Result<FatFile, Error>
     where
         P: AsRef<path::Path>,
@@ -988,9 +1729,111 @@ where
                 .write_data(entry.file_size, output),
         }
     }
+
+    /// Opens a file for reading. Unlike `read`, the file's contents are read on demand as the
+    /// returned handle is read and seeked.
+    pub fn open<P>(&mut self, path: P) -> Result<FatFileReader<'_, 'a, H>, Error>
+    where
+        P: AsRef<path::Path>,
+    {
+        match self.lookup(path)? {
+            FatFile::Directory(_) => Err(FatError::ExpectedFileFoundDirectory.into()),
+            FatFile::Normal(entry) => Ok(FatFileReader {
+                traverser: &mut self.0,
+                entry,
+                position: 0,
+                current_cluster: None,
+            }),
+        }
+    }
 }
 
-#[derive(Debug)]
+pub struct FatFileReader<'r, 'a, H>
+where
+    H: io::Seek + io::Read,
+{
+    traverser: &'r mut Traverser<'a, H>,
+    entry: DirectoryEntry,
+    position: u64,
+    // The most recently visited cluster, and its index within the file's cluster chain. Seeking
+    // forwards continues along the chain from here rather than from the start of the file.
+    current_cluster: Option<(u32, u32)>,
+}
+
+impl<'r, 'a, H> FatFileReader<'r, 'a, H>
+where
+    H: io::Seek + io::Read,
+{
+    pub fn entry(&self) -> &DirectoryEntry {
+        &self.entry
+    }
+
+    fn cluster_at_index(&mut self, index: u32) -> Result<u32, Error> {
+        let (mut current_index, mut cluster) = match self.current_cluster {
+            Some((current_index, cluster)) if current_index <= index => (current_index, cluster),
+            _ => (
+                0,
+                self.traverser.allocated_cluster(self.entry.first_cluster)?,
+            ),
+        };
+        while current_index < index {
+            cluster = self.traverser.next_allocated_cluster(cluster)?;
+            current_index += 1;
+        }
+        self.current_cluster = Some((index, cluster));
+        Ok(cluster)
+    }
+
+    fn read_from_position(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.entry.file_size as u64;
+        if self.position >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let bytes_per_cluster = self.traverser.bpb.bytes_per_cluster() as u64;
+        let cluster = self.cluster_at_index((self.position / bytes_per_cluster) as u32)?;
+        let offset_within_cluster = self.position % bytes_per_cluster;
+        // reads don't cross cluster boundaries as consecutive clusters needn't be contiguous
+        let len = (buf.len() as u64)
+            .min(bytes_per_cluster - offset_within_cluster)
+            .min(size - self.position) as usize;
+        let offset = self.traverser.cluster_byte_offset(cluster) + offset_within_cluster;
+        self.traverser.handle.seek(io::SeekFrom::Start(offset))?;
+        self.traverser.handle.read_exact(&mut buf[0..len])?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'r, 'a, H> io::Read for FatFileReader<'r, 'a, H>
+where
+    H: io::Seek + io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_from_position(buf).map_err(io::Error::other)
+    }
+}
+
+impl<'r, 'a, H> io::Seek for FatFileReader<'r, 'a, H>
+where
+    H: io::Seek + io::Read,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => (self.entry.file_size as u64).checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[derive(Debug)]
 pub struct FatInfo {
     bpb: Bpb,
     fs_info: Option<FsInfo>,
@@ -1004,6 +1847,39 @@ impl FatInfo {
     pub fn num_clusters(&self) -> u32 {
         self.bpb.count_of_clusters()
     }
+    pub fn num_fats(&self) -> u8 {
+        self.bpb.num_fats
+    }
+    /// The index of the FAT which is read. When mirroring is disabled, this is the only FAT
+    /// which is kept up to date.
+    pub fn active_fat(&self) -> u8 {
+        self.bpb.active_fat()
+    }
+    /// Mirroring can only be disabled on FAT32
+    pub fn mirroring_disabled(&self) -> bool {
+        self.bpb.mirroring_disabled()
+    }
+    pub fn fs_info_warning(&self) -> Option<&FsInfoWarning> {
+        self.fs_info_warning.as_ref()
+    }
+    /// The free cluster count recorded in the FsInfo structure, which only exists on FAT32. This
+    /// is only a hint, and is `None` if it is unknown.
+    pub fn num_free_clusters_hint(&self) -> Option<u32> {
+        self.fs_info
+            .as_ref()
+            .map(|fs_info| fs_info.free_count)
+            .filter(|&free_count| free_count != FS_INFO_FREE_COUNT_UNKNOWN)
+    }
+    /// The volume label recorded in the boot sector, without trailing padding
+    pub fn volume_label(&self) -> &str {
+        self.bpb.volume_label.trim_end()
+    }
+    pub fn volume_id(&self) -> u32 {
+        self.bpb.volume_id
+    }
+    pub fn oem_name(&self) -> &str {
+        self.bpb.oem_name.trim_end_matches(['\0', ' '])
+    }
 }
 
 pub fn fat_info<H>(handle: &mut H, partition_byte_range: Range<u64>) -> Result<FatInfo, Error>
@@ -1026,40 +1902,192 @@ where
     })
 }
 
+/// A FAT entry whose value differs between copies of the FAT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FatMismatch {
+    pub cluster: u32,
+    /// The entry's value in each copy of the FAT, in order
+    pub entries: Vec<u32>,
+}
+
+/// Compares every copy of the FAT, returning the entries of data clusters which differ. When
+/// mirroring is disabled only the active FAT is kept up to date, so the copies are expected to
+/// differ.
+pub fn fat_mismatches<H>(
+    handle: &mut H,
+    partition_byte_range: Range<u64>,
+) -> Result<Vec<FatMismatch>, Error>
+where
+    H: io::Seek + io::Read,
+{
+    let mut buf = Vec::new();
+    let bpb = Bpb::read(handle, partition_byte_range.start, &mut buf)?;
+    let fat_type = bpb.fat_type();
+    let fat_size_in_bytes = bpb.fat_size_in_sectors() as usize * bpb.bytes_per_sector as usize;
+    let mut fats = Vec::new();
+    for i in 0..bpb.num_fats {
+        let mut fat = Vec::new();
+        handle_read(
+            handle,
+            partition_byte_range.start + bpb.fat_copy_offset(i),
+            fat_size_in_bytes,
+            &mut fat,
+        )?;
+        fats.push(fat);
+    }
+    let mut mismatches = Vec::new();
+    // entries which don't fit in a malformed FAT which is too small for the volume are ignored
+    let clusters = (2..=bpb.maximum_valid_cluster())
+        .take_while(|&cluster| fat_entry_bytes(fat_type, cluster).end <= fat_size_in_bytes);
+    for cluster in clusters {
+        let entry_bytes = fat_entry_bytes(fat_type, cluster);
+        let entries = fats
+            .iter()
+            .map(|fat| fat_entry_from_bytes(&fat[entry_bytes.clone()], fat_type, cluster))
+            .collect::<Vec<_>>();
+        if entries.iter().any(|&entry| entry != entries[0]) {
+            mismatches.push(FatMismatch { cluster, entries });
+        }
+    }
+    Ok(mismatches)
+}
+
 #[derive(Debug)]
 pub struct PathPair {
     pub in_local_filesystem: File,
     pub in_disk_image: path::PathBuf,
 }
 
-fn round_up_to_nearest_cluster_size(size: u64) -> u64 {
-    if size % create::BYTES_PER_CLUSTER as u64 == 0 {
-        size
-    } else {
-        size + (create::BYTES_PER_CLUSTER as u64 - (size % create::BYTES_PER_CLUSTER as u64))
+fn round_up_to_nearest_cluster_size(size: u64, bytes_per_cluster: u32) -> u64 {
+    size.next_multiple_of(bytes_per_cluster as u64)
+}
+
+const VOLUME_LABEL_SIZE: usize = 11;
+const OEM_NAME_SIZE: usize = 8;
+const PAD_SPACE: u8 = 0x20;
+
+/// Volume labels follow the rules for short names, except that they may contain spaces (though
+/// not at the start), and aren't split into a main part and extension
+fn volume_label_raw(volume_label: &str) -> Result<[u8; VOLUME_LABEL_SIZE], Error> {
+    if volume_label.is_empty()
+        || volume_label.len() > VOLUME_LABEL_SIZE
+        || volume_label.starts_with(' ')
+        || !volume_label
+            .chars()
+            .all(|c| c == ' ' || short_name::is_valid_char(c))
+    {
+        return Err(FatError::InvalidVolumeLabel(volume_label.to_string()).into());
     }
+    let mut raw = [PAD_SPACE; VOLUME_LABEL_SIZE];
+    raw[0..volume_label.len()].copy_from_slice(volume_label.as_bytes());
+    Ok(raw)
+}
+
+fn oem_name_raw(oem_name: &str) -> Result<[u8; OEM_NAME_SIZE], Error> {
+    if oem_name.len() > OEM_NAME_SIZE || !oem_name.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+    {
+        return Err(FatError::InvalidOemName(oem_name.to_string()).into());
+    }
+    let mut raw = [PAD_SPACE; OEM_NAME_SIZE];
+    raw[0..oem_name.len()].copy_from_slice(oem_name.as_bytes());
+    Ok(raw)
+}
+
+fn file_size_u32(file: &File) -> Result<u32, Error> {
+    let size = file.metadata()?.len();
+    if size > u32::MAX as u64 {
+        return Err(FatError::FileTooLarge(size).into());
+    }
+    Ok(size as u32)
 }
 
 mod directory_hierarchy {
     use super::{
-        create, round_up_to_nearest_cluster_size, Error, FatError, PathPair, DIRECTORY_ENTRY_BYTES,
-        NUM_CHARS_PER_LONG_DIRECTORY_ENTRY,
+        create, directory_attributes, file_size_u32, round_up_to_nearest_cluster_size, short_name,
+        Error, FatDate, FatDateTime, FatError, PathPair, RawDirectoryEntryLongName,
+        RawDirectoryEntryNormal, DIRECTORY_ENTRY_BYTES, NUM_CHARS_PER_LONG_DIRECTORY_ENTRY,
+        VOLUME_LABEL_SIZE,
     };
     use std::collections::BTreeMap;
     use std::fs::File;
-    use std::path::{Component, Components};
+    use std::path::{Component, Components, Path};
+    use std::time::SystemTime;
 
     pub type Directory<'a, T> = BTreeMap<String, AnnotatedNode<'a, T>>;
 
-    fn directory_size_in_bytes<T>(directory: &Directory<T>) -> u32 {
-        directory
+    const MAX_LONG_NAME_LEN: usize = 255;
+    const LONG_NAME_INVALID_CHARS: &str = "\"*/:<>?\\|";
+
+    fn directory_short_names<T>(directory: &Directory<T>) -> Vec<short_name::ShortName> {
+        short_name::directory_short_names(directory.keys().map(|name| name.as_str()))
+    }
+
+    fn num_long_directory_entries(name: &str, short_name: &short_name::ShortName) -> u32 {
+        if short_name::needs_long_name(name, short_name) {
+            let name_len_utf16 = name.encode_utf16().count() as u32;
+            name_len_utf16.div_ceil(NUM_CHARS_PER_LONG_DIRECTORY_ENTRY)
+        } else {
+            0
+        }
+    }
+
+    /// Every directory other than the root starts with "." and ".." entries, and the root
+    /// directory starts with the volume label entry if there is one
+    fn num_leading_entries(is_root: bool, settings: &Settings) -> u32 {
+        if is_root {
+            settings.volume_label.is_some() as u32
+        } else {
+            2
+        }
+    }
+
+    fn directory_size_in_bytes<T>(directory: &Directory<T>, num_leading_entries: u32) -> u32 {
+        let num_entries = directory
             .keys()
-            .map(|name| {
-                let num_long_directory_entries =
-                    ((name.len() as u32 - 1) / NUM_CHARS_PER_LONG_DIRECTORY_ENTRY) + 1;
-                (num_long_directory_entries + 1) * DIRECTORY_ENTRY_BYTES
-            })
-            .sum()
+            .zip(directory_short_names(directory).iter())
+            .map(|(name, short_name)| num_long_directory_entries(name, short_name) + 1)
+            .sum::<u32>()
+            + num_leading_entries;
+        num_entries * DIRECTORY_ENTRY_BYTES
+    }
+
+    /// Checks the length and characters of a name. Names must also be distinct within their
+    /// directory when compared case-insensitively.
+    pub fn validate_long_name(name: &str) -> Result<(), Error> {
+        if name.encode_utf16().count() > MAX_LONG_NAME_LEN {
+            return Err(FatError::InvalidDiskPath(format!(
+                "file names must be at most {} characters",
+                MAX_LONG_NAME_LEN
+            ))
+            .into());
+        }
+        if name
+            .chars()
+            .any(|c| c.is_control() || LONG_NAME_INVALID_CHARS.contains(c))
+        {
+            return Err(FatError::InvalidDiskPath(format!(
+                "file names may not contain control characters or any of {}",
+                LONG_NAME_INVALID_CHARS
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn validate_name<T>(directory: &Directory<T>, name: &str) -> Result<(), Error> {
+        validate_long_name(name)?;
+        // FAT file names are case-insensitive
+        let name_upper = name.to_uppercase();
+        if directory
+            .keys()
+            .any(|existing| existing != name && existing.to_uppercase() == name_upper)
+        {
+            return Err(FatError::InvalidDiskPath(
+                "path differs only in case from existant file or directory".to_string(),
+            )
+            .into());
+        }
+        Ok(())
     }
 
     #[derive(Debug)]
@@ -1069,15 +2097,24 @@ mod directory_hierarchy {
     }
 
     impl<'a, T> Node<'a, T> {
-        fn size_in_clusters(&self) -> Result<u32, Error> {
+        fn size_in_clusters(&self, is_root: bool, settings: &Settings) -> Result<u32, Error> {
+            let bytes_per_cluster = settings.bytes_per_cluster;
             let size_in_bytes_rounded_up = match self {
                 Node::Directory(directory) => {
-                    round_up_to_nearest_cluster_size(directory_size_in_bytes(directory) as u64)
+                    // every directory occupies at least one cluster, even when it is empty
+                    round_up_to_nearest_cluster_size(
+                        directory_size_in_bytes(directory, num_leading_entries(is_root, settings))
+                            as u64,
+                        bytes_per_cluster,
+                    )
+                    .max(bytes_per_cluster as u64)
+                }
+                Node::File(file) => {
+                    round_up_to_nearest_cluster_size(file.metadata()?.len(), bytes_per_cluster)
                 }
-                Node::File(file) => round_up_to_nearest_cluster_size(file.metadata()?.len()),
             };
-            debug_assert!(size_in_bytes_rounded_up % create::BYTES_PER_CLUSTER as u64 == 0);
-            Ok((size_in_bytes_rounded_up / create::BYTES_PER_CLUSTER as u64) as u32)
+            debug_assert!(size_in_bytes_rounded_up % bytes_per_cluster as u64 == 0);
+            Ok((size_in_bytes_rounded_up / bytes_per_cluster as u64) as u32)
         }
     }
 
@@ -1085,6 +2122,9 @@ mod directory_hierarchy {
     pub struct AnnotatedNode<'a, T> {
         pub annotation: T,
         pub node: Node<'a, T>,
+        /// Attributes such as `HIDDEN` requested for this node in addition to those derived from
+        /// the node itself
+        pub extra_attributes: u8,
     }
 
     impl<'a> AnnotatedNode<'a, ()> {
@@ -1092,15 +2132,17 @@ mod directory_hierarchy {
             Self {
                 node: Node::Directory(Directory::default()),
                 annotation: (),
+                extra_attributes: 0,
             }
         }
     }
 
+    /// `file` is `None` when inserting an empty directory
     fn directory_insert<'a>(
         directory: &mut Directory<'a, ()>,
         current: Component,
         mut rest: Components,
-        file: &'a File,
+        file: Option<&'a File>,
     ) -> Result<(), Error> {
         if let Component::Normal(os_str) = current {
             let name = os_str
@@ -1111,6 +2153,7 @@ mod directory_hierarchy {
                     )
                 })?
                 .to_string();
+            validate_name(directory, &name)?;
             if let Some(next) = rest.next() {
                 // current component refers to directory
                 match directory
@@ -1128,7 +2171,7 @@ mod directory_hierarchy {
                         .into())
                     }
                 }
-            } else {
+            } else if let Some(file) = file {
                 // current component refers to file
                 if directory.contains_key(&name) {
                     return Err(FatError::InvalidDiskPath(
@@ -1141,8 +2184,21 @@ mod directory_hierarchy {
                     AnnotatedNode {
                         node: Node::File(file),
                         annotation: (),
+                        extra_attributes: 0,
                     },
                 );
+            } else {
+                // current component refers to a directory with no contents of its own, which may
+                // also have been created implicitly by another path
+                let existing = directory
+                    .entry(name)
+                    .or_insert_with(AnnotatedNode::empty_directory_unit_annotation);
+                if let Node::File(_) = existing.node {
+                    return Err(FatError::InvalidDiskPath(
+                        "path refers to existant file".to_string(),
+                    )
+                    .into());
+                }
             }
             Ok(())
         } else {
@@ -1153,16 +2209,22 @@ mod directory_hierarchy {
         }
     }
 
-    fn directory_for_each<'a, T, F: FnMut(&AnnotatedNode<'a, T>)>(
-        directory: &Directory<'a, T>,
+    fn node_for_each<'a, F: FnMut(&AnnotatedNode<'a, ClusterInfo>, Option<u32>)>(
+        annotated_node: &AnnotatedNode<'a, ClusterInfo>,
+        parent_cluster: Option<u32>,
         f: &mut F,
     ) {
-        directory.values().for_each(|node| {
-            f(node);
-            if let Node::Directory(ref subdirectory) = node.node {
-                directory_for_each(subdirectory, f);
-            }
-        })
+        f(annotated_node, parent_cluster);
+        if let Node::Directory(ref directory) = annotated_node.node {
+            // ".." entries refer to the root directory with cluster 0
+            let cluster = match parent_cluster {
+                None => 0,
+                Some(_) => annotated_node.annotation.first_cluster(),
+            };
+            directory
+                .values()
+                .for_each(|annotated_node| node_for_each(annotated_node, Some(cluster), f));
+        }
     }
 
     #[derive(Debug, Default)]
@@ -1171,37 +2233,304 @@ mod directory_hierarchy {
         pub count: u32,
     }
 
-    fn directory_annotate_with_cluster_info<'a>(
-        directory: Directory<'a, ()>,
+    impl ClusterInfo {
+        pub fn first_cluster(&self) -> u32 {
+            // empty files have no clusters, which is indicated by a first cluster of 0
+            if self.count == 0 {
+                0
+            } else {
+                self.start
+            }
+        }
+    }
+
+    fn annotate_with_cluster_info<'a>(
+        annotated_node: AnnotatedNode<'a, ()>,
+        is_root: bool,
+        settings: &Settings,
         next_start_cluster: &mut u32,
-    ) -> Result<Directory<'a, ClusterInfo>, Error> {
-        let mut out: Directory<'a, ClusterInfo> = Default::default();
-        for (file_name, annotated_node) in directory {
-            let start = *next_start_cluster;
-            let count = annotated_node.node.size_in_clusters()?;
-            *next_start_cluster += count;
-            let annotation = ClusterInfo { start, count };
-            let node = match annotated_node.node {
-                Node::Directory(directory) => Node::Directory(
-                    directory_annotate_with_cluster_info(directory, next_start_cluster)?,
-                ),
-                Node::File(file) => Node::File(file),
+    ) -> Result<AnnotatedNode<'a, ClusterInfo>, Error> {
+        let start = *next_start_cluster;
+        let count = if is_root && !settings.root_in_data_region {
+            0
+        } else {
+            annotated_node.node.size_in_clusters(is_root, settings)?
+        };
+        *next_start_cluster += count;
+        let node = match annotated_node.node {
+            Node::Directory(directory) => {
+                let mut out: Directory<'a, ClusterInfo> = Default::default();
+                for (file_name, annotated_node) in directory {
+                    let annotated_node = annotate_with_cluster_info(
+                        annotated_node,
+                        false,
+                        settings,
+                        next_start_cluster,
+                    )?;
+                    out.insert(file_name, annotated_node);
+                }
+                Node::Directory(out)
+            }
+            Node::File(file) => Node::File(file),
+        };
+        let annotation = ClusterInfo { start, count };
+        Ok(AnnotatedNode {
+            node,
+            annotation,
+            extra_attributes: annotated_node.extra_attributes,
+        })
+    }
+
+    pub struct Timestamps {
+        creation: FatDateTime,
+        write: FatDateTime,
+        last_accessed: FatDate,
+    }
+
+    impl Timestamps {
+        pub fn all(time: SystemTime) -> Self {
+            let date_time = FatDateTime::from_system_time(time);
+            Self {
+                creation: date_time,
+                write: date_time,
+                last_accessed: date_time.date,
+            }
+        }
+
+        /// Files take their timestamps from the metadata of the host file, falling back to the
+        /// modification time, then the current time, for timestamps the host doesn't support.
+        /// Timestamps are clamped to be no later than the current time, so that files modified
+        /// after `SOURCE_DATE_EPOCH` don't make an image irreproducible.
+        fn of_node<T>(node: &Node<T>, settings: &Settings) -> Result<Self, Error> {
+            let file = match (node, settings.fixed_timestamp) {
+                (_, Some(fixed_timestamp)) => return Ok(Self::all(fixed_timestamp)),
+                (Node::Directory(_), None) => return Ok(Self::all(settings.current_time)),
+                (Node::File(file), None) => file,
+            };
+            let metadata = file.metadata()?;
+            let clamp =
+                |time: SystemTime| FatDateTime::from_system_time(time.min(settings.current_time));
+            let write = metadata.modified().unwrap_or(settings.current_time);
+            Ok(Self {
+                creation: clamp(metadata.created().unwrap_or(write)),
+                write: clamp(write),
+                last_accessed: clamp(metadata.accessed().unwrap_or(write)).date,
+            })
+        }
+
+        pub fn entry_raw(
+            &self,
+            short_filename_main: String,
+            short_filename_extension: String,
+            attributes: u8,
+            first_cluster: u32,
+            file_size: u32,
+        ) -> RawDirectoryEntryNormal {
+            RawDirectoryEntryNormal {
+                short_filename_main,
+                short_filename_extension,
+                attributes,
+                creation_time_tenth: self.creation.time.raw_tenth(),
+                creation_time: self.creation.time.raw(),
+                creation_date: self.creation.date.raw(),
+                last_accessed_date: self.last_accessed.raw(),
+                first_cluster,
+                write_time: self.write.time.raw(),
+                write_date: self.write.date.raw(),
+                file_size,
+            }
+        }
+    }
+
+    pub fn dot_entry_raw(
+        name: &str,
+        first_cluster: u32,
+        timestamps: &Timestamps,
+    ) -> [u8; DIRECTORY_ENTRY_BYTES as usize] {
+        timestamps
+            .entry_raw(
+                name.to_string(),
+                String::new(),
+                directory_attributes::DIRECTORY,
+                first_cluster,
+                0,
+            )
+            .encode()
+    }
+
+    fn volume_label_entry_raw(
+        volume_label: &[u8; VOLUME_LABEL_SIZE],
+        timestamps: &Timestamps,
+    ) -> [u8; DIRECTORY_ENTRY_BYTES as usize] {
+        // The label occupies the whole 11 byte name field, so it's split where the name field
+        // would be split into the main part and extension of a short name
+        let trimmed = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().to_string();
+        timestamps
+            .entry_raw(
+                trimmed(&volume_label[0..8]),
+                trimmed(&volume_label[8..11]),
+                directory_attributes::VOLUME_ID,
+                0,
+                0,
+            )
+            .encode()
+    }
+
+    /// `parent_cluster` is `None` for the root directory, which has no "." or ".." entries, but
+    /// instead starts with the volume label entry if there is one
+    fn directory_raw(
+        annotated_node: &AnnotatedNode<ClusterInfo>,
+        directory: &Directory<ClusterInfo>,
+        parent_cluster: Option<u32>,
+        settings: &Settings,
+    ) -> Result<Vec<u8>, Error> {
+        let mut raw = Vec::new();
+        let timestamps = Timestamps::of_node(&annotated_node.node, settings)?;
+        match parent_cluster {
+            Some(parent_cluster) => {
+                raw.extend_from_slice(&dot_entry_raw(
+                    ".",
+                    annotated_node.annotation.first_cluster(),
+                    &timestamps,
+                ));
+                raw.extend_from_slice(&dot_entry_raw("..", parent_cluster, &timestamps));
+            }
+            None => {
+                if let Some(ref volume_label) = settings.volume_label {
+                    raw.extend_from_slice(&volume_label_entry_raw(volume_label, &timestamps));
+                }
+            }
+        }
+        for ((name, annotated_node), short_name) in
+            directory.iter().zip(directory_short_names(directory))
+        {
+            let (attributes, file_size) = match annotated_node.node {
+                Node::Directory(_) => (directory_attributes::DIRECTORY, 0),
+                Node::File(file) => {
+                    let read_only = if file.metadata()?.permissions().readonly() {
+                        directory_attributes::READ_ONLY
+                    } else {
+                        0
+                    };
+                    (
+                        directory_attributes::ARCHIVE | read_only,
+                        file_size_u32(file)?,
+                    )
+                }
             };
-            let annotated_node = AnnotatedNode { node, annotation };
-            out.insert(file_name, annotated_node);
+            let needs_long_name = short_name::needs_long_name(name, &short_name);
+            let normal_entry = Timestamps::of_node(&annotated_node.node, settings)?.entry_raw(
+                short_name.main,
+                short_name.extension,
+                attributes | annotated_node.extra_attributes,
+                annotated_node.annotation.first_cluster(),
+                file_size,
+            );
+            if needs_long_name {
+                let checksum = normal_entry.short_name_checksum();
+                for long_name_entry in RawDirectoryEntryLongName::encode_name(name, checksum) {
+                    raw.extend_from_slice(&long_name_entry);
+                }
+            }
+            raw.extend_from_slice(&normal_entry.encode());
+        }
+        Ok(raw)
+    }
+
+    fn root_insert<'a>(
+        root: &mut Directory<'a, ()>,
+        in_disk_image: &Path,
+        file: Option<&'a File>,
+    ) -> Result<(), Error> {
+        let mut components = in_disk_image.components();
+        let first = components.next().ok_or(FatError::InvalidDiskPath(
+            "path must not be empty".to_string(),
+        ))?;
+        if first != Component::RootDir {
+            return Err(FatError::InvalidDiskPath(
+                "paths in disk image must start with root".to_string(),
+            )
+            .into());
+        }
+        let first_non_root = components.next().ok_or(FatError::InvalidDiskPath(
+            "paths must refer to normal file paths - not root directory".to_string(),
+        ))?;
+        directory_insert(root, first_non_root, components, file)
+    }
+
+    fn directory_node_mut<'d, 'a>(
+        directory: &'d mut Directory<'a, ()>,
+        mut components: Components,
+    ) -> Option<&'d mut AnnotatedNode<'a, ()>> {
+        let name = match components.next()? {
+            Component::Normal(os_str) => os_str.to_str()?,
+            _ => return None,
+        };
+        let annotated_node = directory.get_mut(name)?;
+        if components.clone().next().is_none() {
+            return Some(annotated_node);
+        }
+        match annotated_node.node {
+            Node::Directory(ref mut directory) => directory_node_mut(directory, components),
+            Node::File(_) => None,
         }
-        Ok(out)
+    }
+
+    fn root_add_attributes(
+        root: &mut Directory<()>,
+        in_disk_image: &Path,
+        attributes: u8,
+    ) -> Result<(), Error> {
+        let mut components = in_disk_image.components();
+        if components.next() != Some(Component::RootDir) {
+            return Err(FatError::InvalidDiskPath(
+                "paths in disk image must start with root".to_string(),
+            )
+            .into());
+        }
+        let annotated_node = directory_node_mut(root, components).ok_or_else(|| {
+            FatError::InvalidDiskPath(format!(
+                "attributes given for {} which is not in the disk image",
+                in_disk_image.display()
+            ))
+        })?;
+        annotated_node.extra_attributes |= attributes;
+        Ok(())
+    }
+
+    /// Properties of the partition which affect the layout of the directory hierarchy
+    #[derive(Debug)]
+    pub struct Settings {
+        pub bytes_per_cluster: u32,
+        /// On FAT12 and FAT16 the root directory is stored in its own region rather than in
+        /// clusters
+        pub root_in_data_region: bool,
+        /// Written as an entry at the start of the root directory if present
+        pub volume_label: Option<[u8; VOLUME_LABEL_SIZE]>,
+        /// Used for all timestamps instead of the metadata of files if present
+        pub fixed_timestamp: Option<SystemTime>,
+        /// Used for timestamps of directories, and as an upper bound for timestamps of files
+        pub current_time: SystemTime,
     }
 
     #[derive(Debug)]
     pub struct DirectoryHierarchy<'a> {
-        root: Directory<'a, ClusterInfo>,
+        root: AnnotatedNode<'a, ClusterInfo>,
+        settings: Settings,
     }
 
     impl<'a> DirectoryHierarchy<'a> {
-        pub fn new<I>(path_pairs: I) -> Result<Self, Error>
+        /// `attributes` are added to the attributes of the files or directories at the given paths
+        pub fn new<I, D, A>(
+            path_pairs: I,
+            directories: D,
+            attributes: A,
+            settings: Settings,
+        ) -> Result<Self, Error>
         where
             I: IntoIterator<Item = &'a PathPair>,
+            D: IntoIterator<Item = &'a Path>,
+            A: IntoIterator<Item = (&'a Path, u8)>,
         {
             let mut root_unsized = Default::default();
             for PathPair {
@@ -1209,75 +2538,81 @@ mod directory_hierarchy {
                 in_disk_image,
             } in path_pairs
             {
-                let mut components = in_disk_image.components();
-                let first = components.next().ok_or(FatError::InvalidDiskPath(
-                    "path must not be empty".to_string(),
-                ))?;
-                if first != Component::RootDir {
-                    return Err(FatError::InvalidDiskPath(
-                        "paths in disk image must start with root".to_string(),
-                    )
-                    .into());
-                }
-                let first_non_root = components.next().ok_or(FatError::InvalidDiskPath(
-                    "paths must refer to normal file paths - not root directory".to_string(),
-                ))?;
-                directory_insert(
-                    &mut root_unsized,
-                    first_non_root,
-                    components,
-                    &in_local_filesystem,
-                )?;
+                root_insert(&mut root_unsized, in_disk_image, Some(in_local_filesystem))?;
             }
-            // The first cluster will be cluster 2. The root directory will always be the first
-            // file visited by directory_map.
-            let mut next_start_cluster = 2;
-            let root = directory_annotate_with_cluster_info(root_unsized, &mut next_start_cluster)?;
-            let out = Self { root };
+            for in_disk_image in directories {
+                root_insert(&mut root_unsized, in_disk_image, None)?;
+            }
+            for (in_disk_image, attributes) in attributes {
+                root_add_attributes(&mut root_unsized, in_disk_image, attributes)?;
+            }
+            // The root directory is allocated first, so it will start at the root cluster. The
+            // remaining files are allocated in the order they are visited by for_each.
+            let mut next_start_cluster = create::ROOT_CLUSTER;
+            let root = annotate_with_cluster_info(
+                AnnotatedNode {
+                    node: Node::Directory(root_unsized),
+                    annotation: (),
+                    extra_attributes: 0,
+                },
+                true,
+                &settings,
+                &mut next_start_cluster,
+            )?;
+            debug_assert_eq!(root.annotation.start, create::ROOT_CLUSTER);
+            let out = Self { root, settings };
             Ok(out)
         }
 
-        pub fn for_each<F: FnMut(&AnnotatedNode<'a, ClusterInfo>)>(&self, mut f: F) {
-            directory_for_each(&self.root, &mut f);
+        pub fn root(&self) -> &AnnotatedNode<'a, ClusterInfo> {
+            &self.root
         }
 
-        pub fn implied_num_data_clusters(&self) -> Result<u32, Error> {
-            let mut total_file_size = 0;
-            let mut error = None;
-            self.for_each(|annotated_node| {
-                if error.is_none() {
-                    match annotated_node.node.size_in_clusters() {
-                        Ok(size_in_clusters) => {
-                            total_file_size += size_in_clusters * create::BYTES_PER_CLUSTER
-                        }
-                        Err(e) => error = Some(e),
-                    }
+        /// Visits the root directory followed by every file and directory beneath it. Nodes are
+        /// visited in the order of their clusters. Along with each node, `f` is passed the cluster
+        /// which the node's ".." entry would refer to, or `None` for the root directory.
+        pub fn for_each<F: FnMut(&AnnotatedNode<'a, ClusterInfo>, Option<u32>)>(&self, mut f: F) {
+            node_for_each(&self.root, None, &mut f);
+        }
+
+        /// Like `for_each`, but skips the root directory if it isn't stored in clusters
+        pub fn for_each_in_data_region<F: FnMut(&AnnotatedNode<'a, ClusterInfo>, Option<u32>)>(
+            &self,
+            mut f: F,
+        ) {
+            let root_in_data_region = self.settings.root_in_data_region;
+            self.for_each(|annotated_node, parent_cluster| {
+                if parent_cluster.is_some() || root_in_data_region {
+                    f(annotated_node, parent_cluster);
                 }
             });
-            if let Some(e) = error {
-                return Err(e);
+        }
+
+        pub fn num_used_data_clusters(&self) -> u32 {
+            let mut num_clusters = 0;
+            self.for_each(|annotated_node, _| num_clusters += annotated_node.annotation.count);
+            num_clusters
+        }
+
+        pub fn num_root_directory_entries(&self) -> u32 {
+            match self.root.node {
+                Node::Directory(ref directory) => {
+                    directory_size_in_bytes(directory, num_leading_entries(true, &self.settings))
+                        / DIRECTORY_ENTRY_BYTES
+                }
+                Node::File(_) => unreachable!("root is always a directory"),
             }
-            debug_assert!(total_file_size % create::BYTES_PER_CLUSTER == 0);
-            total_file_size =
-                total_file_size.max(create::MIN_NUM_CLUSTERS as u32 * create::BYTES_PER_CLUSTER);
-            let num_clusters = total_file_size / create::BYTES_PER_CLUSTER;
-            assert!(num_clusters <= u32::MAX);
-            Ok(num_clusters as u32)
         }
 
-        pub fn implied_total_num_clusters(&self) -> Result<u64, Error> {
-            let num_clusters = self.implied_num_data_clusters()? as u64;
-            // Add 2 to the number of clusters to account for the fact that the first 2 FAT entries
-            // are unused.
-            let fat_size =
-                (num_clusters + 2) * create::FAT_ENTRY_SIZE_BYTES as u64 * create::NUM_FATS as u64;
-            let reserved_size =
-                create::RESERVED_SECTOR_COUNT as u64 * create::BYTES_PER_SECTOR as u64;
-            let num_bytes = num_clusters * create::BYTES_PER_CLUSTER as u64
-                + round_up_to_nearest_cluster_size(fat_size)
-                + reserved_size;
-            let num_clusters = num_bytes / (create::BYTES_PER_CLUSTER as u64);
-            Ok(num_clusters)
+        /// The contents of a directory node in the hierarchy. `parent_cluster` is as passed to the
+        /// function given to `for_each`.
+        pub fn directory_raw(
+            &self,
+            annotated_node: &AnnotatedNode<ClusterInfo>,
+            directory: &Directory<ClusterInfo>,
+            parent_cluster: Option<u32>,
+        ) -> Result<Vec<u8>, Error> {
+            directory_raw(annotated_node, directory, parent_cluster, &self.settings)
         }
     }
 }
@@ -1286,57 +2621,191 @@ pub fn partition_size<'a, I>(path_pairs: I) -> Result<u64, Error>
 where
     I: IntoIterator<Item = &'a PathPair>,
 {
-    use directory_hierarchy::DirectoryHierarchy;
-    let num_clusters = DirectoryHierarchy::new(path_pairs)?.implied_total_num_clusters()?;
-    Ok(num_clusters * create::BYTES_PER_CLUSTER as u64)
+    FatBuilder::new().path_pairs(path_pairs).partition_size()
 }
 
-fn write_zero_sector<H>(handle: &mut H) -> Result<(), Error>
+fn write_zeros<H>(handle: &mut H, count: u64) -> Result<(), Error>
 where
     H: io::Write,
 {
-    handle.write_all(&[0; create::BYTES_PER_SECTOR as usize])?;
+    use io::Read;
+    io::copy(&mut io::repeat(0).take(count), handle)?;
     Ok(())
 }
 
-fn write_fat32_fat<H>(
+/// The range of bytes within the FAT which contain the nth entry. On FAT12 these bytes are
+/// shared with a neighbouring entry.
+fn fat_entry_bytes(fat_type: FatType, n: u32) -> Range<usize> {
+    let base = match fat_type {
+        FatType::Fat32 => n as usize * 4,
+        FatType::Fat16 => n as usize * 2,
+        FatType::Fat12 => n as usize + (n as usize / 2),
+    };
+    let len = if fat_type == FatType::Fat32 { 4 } else { 2 };
+    base..(base + len)
+}
+
+/// Updates the nth entry within the bytes returned by `fat_entry_bytes`, preserving the bits of
+/// any neighbouring entry and the reserved high bits of FAT32 entries
+fn set_fat_entry_bytes(bytes: &mut [u8], fat_type: FatType, n: u32, value: u32) {
+    use std::convert::TryInto;
+    match fat_type {
+        FatType::Fat32 => {
+            let existing = u32::from_le_bytes(bytes.try_into().unwrap());
+            let entry = (existing & 0xF0000000) | (value & 0x0FFFFFFF);
+            bytes.copy_from_slice(&entry.to_le_bytes());
+        }
+        FatType::Fat16 => {
+            bytes.copy_from_slice(&(value as u16).to_le_bytes());
+        }
+        FatType::Fat12 => {
+            let existing = u16::from_le_bytes(bytes.try_into().unwrap());
+            let value = value as u16 & 0xFFF;
+            let entry16 = if n & 1 == 0 {
+                (existing & 0xF000) | value
+            } else {
+                (existing & 0x000F) | (value << 4)
+            };
+            bytes.copy_from_slice(&entry16.to_le_bytes());
+        }
+    }
+}
+
+/// Decodes the nth entry from the bytes returned by `fat_entry_bytes`, ignoring the reserved high
+/// bits of FAT32 entries
+fn fat_entry_from_bytes(bytes: &[u8], fat_type: FatType, n: u32) -> u32 {
+    use std::convert::TryInto;
+    match fat_type {
+        FatType::Fat32 => u32::from_le_bytes(bytes.try_into().unwrap()) & 0x0FFFFFFF,
+        FatType::Fat16 => u16::from_le_bytes(bytes.try_into().unwrap()) as u32,
+        FatType::Fat12 => {
+            let entry16 = u16::from_le_bytes(bytes.try_into().unwrap());
+            if n & 1 == 0 {
+                (entry16 & 0xFFF) as u32
+            } else {
+                (entry16 >> 4) as u32
+            }
+        }
+    }
+}
+
+/// Sets the `n`th entry of a FAT stored in `fat`, preserving the bits of neighbouring FAT12
+/// entries and the reserved high bits of FAT32 entries
+fn set_fat_entry(fat: &mut [u8], fat_type: FatType, n: u32, value: u32) {
+    set_fat_entry_bytes(&mut fat[fat_entry_bytes(fat_type, n)], fat_type, n, value);
+}
+
+fn fat_raw(
+    directory_hierarchy: &directory_hierarchy::DirectoryHierarchy,
+    layout: &create::Layout,
+) -> Vec<u8> {
+    let fat_type = layout.fat_type;
+    let end_of_chain = fat_type.fat_entry_end_of_chain();
+    // The FAT is padded to fill its final sector. Clusters without entries below are free.
+    let mut fat = vec![0; layout.fat_size_in_sectors as usize * layout.bytes_per_sector as usize];
+    // The first 2 entries don't correspond to clusters. By convention, the first contains the
+    // media type and the second contains an end of file marker.
+    set_fat_entry(
+        &mut fat,
+        fat_type,
+        0,
+        (end_of_chain & !0xFF) | create::MEDIA_FIXED as u32,
+    );
+    set_fat_entry(&mut fat, fat_type, 1, end_of_chain);
+    directory_hierarchy.for_each_in_data_region(|annotated_node, _| {
+        let directory_hierarchy::ClusterInfo { start, count } = annotated_node.annotation;
+        if count == 0 {
+            return;
+        }
+        for cluster_index in start..(start + count - 1) {
+            set_fat_entry(&mut fat, fat_type, cluster_index, cluster_index + 1);
+        }
+        set_fat_entry(&mut fat, fat_type, start + count - 1, end_of_chain);
+    });
+    fat
+}
+
+fn write_node_clusters<H>(
     handle: &mut H,
     directory_hierarchy: &directory_hierarchy::DirectoryHierarchy,
+    annotated_node: &directory_hierarchy::AnnotatedNode<directory_hierarchy::ClusterInfo>,
+    parent_cluster: Option<u32>,
+    layout: &create::Layout,
 ) -> Result<(), Error>
 where
     H: io::Write,
 {
-    const FAT32_ENTRY_END_OF_FILE: u32 = 0xFFFFFFFF;
-    let mut error = None;
-    // Skip the first 2 entries
-    handle.write_all(&0u32.to_le_bytes())?;
-    handle.write_all(&0u32.to_le_bytes())?;
-    let mut entry_count = 2;
-    directory_hierarchy.for_each(|annotated_node| {
-        if error.is_some() {
-            return;
+    use directory_hierarchy::Node;
+    use io::{Read, Seek};
+    let size = annotated_node.annotation.count as u64 * layout.bytes_per_cluster() as u64;
+    let num_bytes_written = match annotated_node.node {
+        Node::Directory(ref directory) => {
+            let directory_raw =
+                directory_hierarchy.directory_raw(annotated_node, directory, parent_cluster)?;
+            handle.write_all(&directory_raw)?;
+            directory_raw.len() as u64
         }
-        for cluster_index in (annotated_node.annotation.start + 1)
-            ..(annotated_node.annotation.start + annotated_node.annotation.count)
-        {
-            if let Err(e) = handle.write_all(&cluster_index.to_le_bytes()) {
-                error = Some(e);
-                return;
-            }
+        Node::File(mut file) => {
+            file.seek(io::SeekFrom::Start(0))?;
+            io::copy(&mut file.take(size), handle)?
         }
-        if let Err(e) = handle.write_all(&FAT32_ENTRY_END_OF_FILE.to_le_bytes()) {
-            error = Some(e);
+    };
+    // pad the final cluster with zeroes
+    write_zeros(handle, size - num_bytes_written)
+}
+
+/// Writes the fixed-size root directory region which precedes the data region on FAT12 and FAT16
+fn write_root_directory_region<H>(
+    handle: &mut H,
+    directory_hierarchy: &directory_hierarchy::DirectoryHierarchy,
+    layout: &create::Layout,
+) -> Result<(), Error>
+where
+    H: io::Write,
+{
+    use directory_hierarchy::Node;
+    let root = directory_hierarchy.root();
+    let directory_raw = match root.node {
+        Node::Directory(ref directory) => {
+            directory_hierarchy.directory_raw(root, directory, None)?
+        }
+        Node::File(_) => unreachable!("root is always a directory"),
+    };
+    let size = layout.root_directory_sectors() as u64 * layout.bytes_per_sector as u64;
+    handle.write_all(&directory_raw)?;
+    write_zeros(handle, size - directory_raw.len() as u64)
+}
+
+fn write_data_region<H>(
+    handle: &mut H,
+    directory_hierarchy: &directory_hierarchy::DirectoryHierarchy,
+    layout: &create::Layout,
+) -> Result<(), Error>
+where
+    H: io::Write,
+{
+    let mut error = None;
+    directory_hierarchy.for_each_in_data_region(|annotated_node, parent_cluster| {
+        if error.is_none() {
+            error = write_node_clusters(
+                handle,
+                directory_hierarchy,
+                annotated_node,
+                parent_cluster,
+                layout,
+            )
+            .err();
         }
-        entry_count += annotated_node.annotation.count;
     });
     if let Some(e) = error {
-        return Err(e.into());
+        return Err(e);
     }
-    let remaining_fat_entry_count = directory_hierarchy.implied_num_data_clusters()? - entry_count;
-    for _ in 0..remaining_fat_entry_count {
-        handle.write_all(&0u32.to_le_bytes())?;
-    }
-    Ok(())
+    // free clusters, followed by any sectors which don't make up a whole cluster
+    write_zeros(
+        handle,
+        (layout.num_free_data_clusters() as u64 * layout.bytes_per_cluster() as u64)
+            + (layout.num_unused_sectors() * layout.bytes_per_sector as u64),
+    )
 }
 
 pub fn write_partition<'a, H, I>(handle: &mut H, path_pairs: I) -> Result<(), Error>
@@ -1344,33 +2813,324 @@ where
     H: io::Write,
     I: IntoIterator<Item = &'a PathPair>,
 {
-    use directory_hierarchy::DirectoryHierarchy;
-    let hierarchy = DirectoryHierarchy::new(path_pairs)?;
-    let bpb_raw = Bpb::new_fat32_raw(&hierarchy)?;
-    let bpb = match Bpb::parse(&bpb_raw) {
-        Ok(bpb) => bpb,
-        Err(ref e) => {
-            eprintln!("Failed to parse generated BPB");
-            die(e);
+    FatBuilder::new().path_pairs(path_pairs).write(handle)
+}
+
+/// The size of a FAT partition to create
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionSize {
+    /// A fixed size, which is rounded down to a whole number of sectors
+    Bytes(u64),
+    /// Free space in addition to the space needed for the contents
+    FreeBytes(u64),
+    /// Free space in addition to the space needed for the contents, as a percentage of the size
+    /// of the partition without free space
+    FreePercent(u32),
+}
+
+/// Describes the contents of a FAT partition to create
+#[derive(Debug, Default)]
+pub struct FatBuilder<'a> {
+    path_pairs: Vec<&'a PathPair>,
+    directories: Vec<path::PathBuf>,
+    volume_label: Option<String>,
+    volume_id: Option<u32>,
+    oem_name: Option<String>,
+    timestamp: Option<SystemTime>,
+    attributes: Vec<(path::PathBuf, u8)>,
+    fat_type: Option<FatType>,
+    bytes_per_sector: Option<u16>,
+    sectors_per_cluster: Option<u8>,
+    size: Option<PartitionSize>,
+}
+
+impl<'a> FatBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn path_pair(mut self, path_pair: &'a PathPair) -> Self {
+        self.path_pairs.push(path_pair);
+        self
+    }
+
+    pub fn path_pairs<I>(mut self, path_pairs: I) -> Self
+    where
+        I: IntoIterator<Item = &'a PathPair>,
+    {
+        self.path_pairs.extend(path_pairs);
+        self
+    }
+
+    /// Creates a directory in the disk image, along with any missing parents. This is only
+    /// necessary for directories which would otherwise be empty, as the parent directories of
+    /// files are created implicitly.
+    pub fn directory<P>(mut self, in_disk_image: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        self.directories.push(in_disk_image.into());
+        self
+    }
+
+    /// By default the smallest FAT type which can hold the contents is used. Forcing a larger
+    /// type pads the partition with free clusters to meet that type's minimum cluster count.
+    pub fn fat_type(mut self, fat_type: FatType) -> Self {
+        self.fat_type = Some(fat_type);
+        self
+    }
+
+    /// One of 512, 1024, 2048 or 4096. Defaults to 512.
+    pub fn bytes_per_sector(mut self, bytes_per_sector: u16) -> Self {
+        self.bytes_per_sector = Some(bytes_per_sector);
+        self
+    }
+
+    /// A power of 2 no greater than 128. By default this is chosen based on the size of the
+    /// partition, in the same way as mkfs.fat.
+    pub fn sectors_per_cluster(mut self, sectors_per_cluster: u8) -> Self {
+        self.sectors_per_cluster = Some(sectors_per_cluster);
+        self
+    }
+
+    /// Upper case letters, digits, spaces and the characters allowed in short names, up to 11
+    /// characters. When a label is set, it's also written as an entry in the root directory.
+    pub fn volume_label(mut self, volume_label: &str) -> Self {
+        self.volume_label = Some(volume_label.to_string());
+        self
+    }
+
+    /// By default the volume ID is derived from the current time. `SOURCE_DATE_EPOCH` isn't used
+    /// here, as it's shared by every image in a build and volume IDs should differ.
+    pub fn volume_id(mut self, volume_id: u32) -> Self {
+        self.volume_id = Some(volume_id);
+        self
+    }
+
+    /// At most 8 printable ASCII characters. Defaults to "mini_fat".
+    pub fn oem_name(mut self, oem_name: &str) -> Self {
+        self.oem_name = Some(oem_name.to_string());
+        self
+    }
+
+    /// Use a fixed time for the timestamps of all files and directories. By default files take
+    /// their timestamps and read-only attribute from the metadata of the local file, and
+    /// directories are timestamped with the current time, or `SOURCE_DATE_EPOCH` if it is set.
+    pub fn timestamp(mut self, timestamp: SystemTime) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Sets the hidden attribute of a file or directory in the disk image
+    pub fn hidden<P>(mut self, in_disk_image: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        self.attributes
+            .push((in_disk_image.into(), directory_attributes::HIDDEN));
+        self
+    }
+
+    /// Sets the system attribute of a file or directory in the disk image
+    pub fn system<P>(mut self, in_disk_image: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        self.attributes
+            .push((in_disk_image.into(), directory_attributes::SYSTEM));
+        self
+    }
+
+    fn directory_hierarchy(
+        &self,
+        bytes_per_cluster: u32,
+        root_in_data_region: bool,
+    ) -> Result<directory_hierarchy::DirectoryHierarchy<'_>, Error> {
+        let volume_label = match self.volume_label {
+            Some(ref volume_label) => Some(volume_label_raw(volume_label)?),
+            None => None,
+        };
+        directory_hierarchy::DirectoryHierarchy::new(
+            self.path_pairs.iter().cloned(),
+            self.directories.iter().map(|path| path.as_path()),
+            self.attributes
+                .iter()
+                .map(|(path, attributes)| (path.as_path(), *attributes)),
+            directory_hierarchy::Settings {
+                bytes_per_cluster,
+                root_in_data_region,
+                volume_label,
+                fixed_timestamp: self.timestamp,
+                current_time: create::current_time()?,
+            },
+        )
+    }
+
+    /// By default partitions are just large enough to hold their contents
+    pub fn size(mut self, size: PartitionSize) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    fn sectors_per_cluster_for(&self, bytes_per_sector: u16) -> Result<u8, Error> {
+        if let Some(sectors_per_cluster) = self.sectors_per_cluster {
+            create::validate_sectors_per_cluster(sectors_per_cluster)?;
+            return Ok(sectors_per_cluster);
         }
-    };
-    let _fat_type = bpb.fat_type(); // sanity check
-    let fs_info_raw = FsInfo::new_raw(0, hierarchy.implied_num_data_clusters()?);
-    if let Err(ref e) = FsInfo::parse(&fs_info_raw) {
-        eprintln!("Failed to parse generated FsInfo");
-        die(e);
-    }
-    handle.write_all(&bpb_raw)?; // sector 0
-    handle.write_all(&fs_info_raw)?; // sector 1
-    for _ in 2..create::BK_BOOT_SECTOR {
-        write_zero_sector(handle)?;
-    }
-    handle.write_all(&bpb_raw)?; // sector 6
-    handle.write_all(&fs_info_raw)?; // sector 7
-    for _ in 0..create::NUM_FATS {
-        write_fat32_fat(handle, &hierarchy)?;
+        // the size of the contents when packed as tightly as possible stands in for the volume
+        // size when it isn't specified
+        let contents_size_in_bytes = self
+            .directory_hierarchy(bytes_per_sector as u32, false)?
+            .num_used_data_clusters() as u64
+            * bytes_per_sector as u64;
+        let volume_size_in_bytes = match self.size {
+            None => contents_size_in_bytes,
+            Some(PartitionSize::Bytes(size_in_bytes)) => size_in_bytes,
+            Some(PartitionSize::FreeBytes(free_bytes)) => contents_size_in_bytes + free_bytes,
+            Some(PartitionSize::FreePercent(free_percent)) => {
+                contents_size_in_bytes + (contents_size_in_bytes * free_percent as u64 / 100)
+            }
+        };
+        let bytes_per_cluster =
+            create::default_bytes_per_cluster(self.fat_type, volume_size_in_bytes);
+        Ok((bytes_per_cluster / bytes_per_sector as u32)
+            .clamp(1, create::MAX_SECTORS_PER_CLUSTER as u32) as u8)
+    }
+
+    /// Tries each candidate FAT type in turn, returning the error from the first if none fit
+    fn directory_hierarchy_and_layout_with_total_num_sectors(
+        &self,
+        bytes_per_sector: u16,
+        sectors_per_cluster: u8,
+        total_num_sectors: Option<u64>,
+    ) -> Result<(directory_hierarchy::DirectoryHierarchy<'_>, create::Layout), Error> {
+        let bytes_per_cluster = bytes_per_sector as u32 * sectors_per_cluster as u32;
+        let fat_types = match self.fat_type {
+            Some(ref fat_type) => std::slice::from_ref(fat_type),
+            None => create::FAT_TYPES_SMALLEST_FIRST,
+        };
+        let mut first_error = None;
+        for &fat_type in fat_types {
+            let hierarchy =
+                self.directory_hierarchy(bytes_per_cluster, fat_type == FatType::Fat32)?;
+            match create::Layout::new(
+                fat_type,
+                bytes_per_sector,
+                sectors_per_cluster,
+                hierarchy.num_used_data_clusters(),
+                hierarchy.num_root_directory_entries(),
+                total_num_sectors,
+            ) {
+                Ok(layout) => return Ok((hierarchy, layout)),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap())
+    }
+
+    fn directory_hierarchy_and_layout(
+        &self,
+    ) -> Result<(directory_hierarchy::DirectoryHierarchy<'_>, create::Layout), Error> {
+        let bytes_per_sector = self
+            .bytes_per_sector
+            .unwrap_or(create::DEFAULT_BYTES_PER_SECTOR);
+        create::validate_bytes_per_sector(bytes_per_sector)?;
+        let sectors_per_cluster = self.sectors_per_cluster_for(bytes_per_sector)?;
+        let free_bytes = |contents_layout: &create::Layout| match self.size {
+            Some(PartitionSize::FreeBytes(free_bytes)) => free_bytes,
+            Some(PartitionSize::FreePercent(free_percent)) => {
+                contents_layout.total_num_bytes() * free_percent as u64 / 100
+            }
+            Some(PartitionSize::Bytes(_)) | None => 0,
+        };
+        let total_num_sectors = match self.size {
+            None => None,
+            Some(PartitionSize::Bytes(size_in_bytes)) => {
+                Some(size_in_bytes / bytes_per_sector as u64)
+            }
+            Some(PartitionSize::FreeBytes(_)) | Some(PartitionSize::FreePercent(_)) => {
+                let (_, contents_layout) = self
+                    .directory_hierarchy_and_layout_with_total_num_sectors(
+                        bytes_per_sector,
+                        sectors_per_cluster,
+                        None,
+                    )?;
+                Some(
+                    contents_layout.total_num_sectors
+                        + free_bytes(&contents_layout).div_ceil(bytes_per_sector as u64),
+                )
+            }
+        };
+        self.directory_hierarchy_and_layout_with_total_num_sectors(
+            bytes_per_sector,
+            sectors_per_cluster,
+            total_num_sectors,
+        )
+    }
+
+    pub fn partition_size(&self) -> Result<u64, Error> {
+        let (_, layout) = self.directory_hierarchy_and_layout()?;
+        Ok(layout.total_num_bytes())
+    }
+
+    pub fn write<H>(&self, handle: &mut H) -> Result<(), Error>
+    where
+        H: io::Write,
+    {
+        let (hierarchy, layout) = self.directory_hierarchy_and_layout()?;
+        let volume_label =
+            volume_label_raw(self.volume_label.as_deref().unwrap_or(create::VOLUME_LABEL))?;
+        let oem_name = oem_name_raw(self.oem_name.as_deref().unwrap_or(create::OEM_NAME))?;
+        let volume_id = match self.volume_id {
+            Some(volume_id) => volume_id,
+            None => create::volume_id_from_time(SystemTime::now()),
+        };
+        let bpb_raw = Bpb::new_raw(&layout, &oem_name, volume_id, &volume_label);
+        let bpb = match Bpb::parse(&bpb_raw) {
+            Ok(bpb) => bpb,
+            Err(ref e) => {
+                eprintln!("Failed to parse generated BPB");
+                die(e);
+            }
+        };
+        // sanity check
+        assert_eq!(bpb.fat_type(), layout.fat_type);
+        let sector_offset = |sector: u16| sector as usize * layout.bytes_per_sector as usize;
+        let mut reserved_region = vec![0; sector_offset(layout.reserved_sector_count)];
+        reserved_region[0..BPB_SIZE].copy_from_slice(&bpb_raw);
+        if layout.fat_type == FatType::Fat32 {
+            let fs_info_raw = FsInfo::new_raw(
+                layout.num_free_data_clusters(),
+                layout
+                    .first_free_cluster()
+                    .unwrap_or(FS_INFO_NEXT_FREE_UNKNOWN),
+            );
+            if let Err(ref e) = FsInfo::parse(&fs_info_raw) {
+                eprintln!("Failed to parse generated FsInfo");
+                die(e);
+            }
+            let fs_info_offset = sector_offset(create::FS_INFO);
+            let bk_boot_offset = sector_offset(create::BK_BOOT_SECTOR);
+            let bk_fs_info_offset = sector_offset(create::BK_BOOT_SECTOR + create::FS_INFO);
+            reserved_region[fs_info_offset..(fs_info_offset + FS_INFO_SIZE)]
+                .copy_from_slice(&fs_info_raw);
+            reserved_region[bk_boot_offset..(bk_boot_offset + BPB_SIZE)].copy_from_slice(&bpb_raw);
+            reserved_region[bk_fs_info_offset..(bk_fs_info_offset + FS_INFO_SIZE)]
+                .copy_from_slice(&fs_info_raw);
+        }
+        handle.write_all(&reserved_region)?;
+        let fat = fat_raw(&hierarchy, &layout);
+        for _ in 0..create::NUM_FATS {
+            handle.write_all(&fat)?;
+        }
+        if layout.fat_type != FatType::Fat32 {
+            write_root_directory_region(handle, &hierarchy, &layout)?;
+        }
+        write_data_region(handle, &hierarchy, &layout)?;
+        Ok(())
     }
-    Ok(())
 }
 
 fn die(error: &Error) -> ! {
@@ -1379,3 +3139,918 @@ fn die(error: &Error) -> ! {
     eprintln!("{}", error.backtrace());
     std::process::exit(1);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!("mini_fat_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        let file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    /// Writes an image of each FAT type from the builder returned by `builder`, along with the
+    /// byte range of the partition within the image
+    fn images<'b>(
+        builder: impl 'b + Fn() -> FatBuilder<'b>,
+    ) -> impl 'b + Iterator<Item = (FatType, io::Cursor<Vec<u8>>, Range<u64>)> {
+        [
+            (FatType::Fat12, 1 << 20),
+            (FatType::Fat16, 20 << 20),
+            (FatType::Fat32, 40 << 20),
+        ]
+        .iter()
+        .map(move |&(fat_type, size)| {
+            let fat = builder()
+                .fat_type(fat_type)
+                .size(PartitionSize::Bytes(size));
+            let mut image = io::Cursor::new(Vec::new());
+            fat.write(&mut image).unwrap();
+            let partition_byte_range = 0..(image.get_ref().len() as u64);
+            (fat_type, image, partition_byte_range)
+        })
+    }
+
+    #[test]
+    fn write_then_read() {
+        let large = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+        let path_pairs = vec![
+            PathPair {
+                in_local_filesystem: temp_file("hello", b"hello"),
+                in_disk_image: "/hello.txt".into(),
+            },
+            PathPair {
+                in_local_filesystem: temp_file("large", &large),
+                in_disk_image: "/EFI/BOOT/A Long File Name.efi".into(),
+            },
+            PathPair {
+                in_local_filesystem: temp_file("empty", &[]),
+                in_disk_image: "/EFI/empty".into(),
+            },
+        ];
+        let mut image = io::Cursor::new(Vec::new());
+        write_partition(&mut image, &path_pairs).unwrap();
+        assert_eq!(
+            image.get_ref().len() as u64,
+            partition_size(&path_pairs).unwrap()
+        );
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+        let mut output = Vec::new();
+        reader.read("/hello.txt", &mut output).unwrap();
+        assert_eq!(output, b"hello");
+        output.clear();
+        reader
+            .read("/EFI/BOOT/A Long File Name.efi", &mut output)
+            .unwrap();
+        assert_eq!(output, large);
+        output.clear();
+        reader.read("/EFI/empty", &mut output).unwrap();
+        assert!(output.is_empty());
+        let directory_entries = |reader: &mut FatReader<_>, path| match reader.lookup(path) {
+            Ok(FatFile::Directory(directory)) => directory.entries().to_vec(),
+            _ => panic!("expected directory"),
+        };
+        let root_entries = directory_entries(&mut reader, "/");
+        assert!(root_entries.iter().all(|e| e.name() != "."));
+        let efi_entries = directory_entries(&mut reader, "/EFI");
+        let names = efi_entries.iter().map(|e| e.name()).collect::<Vec<_>>();
+        assert_eq!(names, vec![".", "..", "BOOT", "empty"]);
+        // "." refers to the directory itself and ".." refers to the root directory as cluster 0
+        assert_eq!(
+            efi_entries[0].first_cluster(),
+            root_entries[0].first_cluster()
+        );
+        assert_eq!(efi_entries[1].first_cluster(), 0);
+        let boot_entries = directory_entries(&mut reader, "/EFI/BOOT");
+        assert_eq!(
+            boot_entries[1].first_cluster(),
+            efi_entries[0].first_cluster()
+        );
+    }
+
+    #[test]
+    fn empty_directories() {
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("empty_directories", b"x"),
+            in_disk_image: "/EFI/BOOT/BOOTX64.EFI".into(),
+        }];
+        let fat = FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .directory("/logs")
+            .directory("/EFI/vendor/keys")
+            .directory("/EFI/BOOT");
+        let mut image = io::Cursor::new(Vec::new());
+        fat.write(&mut image).unwrap();
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+        for path in &["/logs", "/EFI/vendor/keys", "/EFI/BOOT"] {
+            assert!(matches!(reader.lookup(path), Ok(FatFile::Directory(_))));
+        }
+        let invalid = FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .directory("/EFI/BOOT/BOOTX64.EFI");
+        assert!(invalid.partition_size().is_err());
+    }
+
+    #[test]
+    fn fat_types() {
+        let large = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+        let path_pairs = vec![
+            PathPair {
+                in_local_filesystem: temp_file("fat_types_hello", b"hello"),
+                in_disk_image: "/hello.txt".into(),
+            },
+            PathPair {
+                in_local_filesystem: temp_file("fat_types_large", &large),
+                in_disk_image: "/EFI/BOOT/BOOTX64.EFI".into(),
+            },
+        ];
+        // small contents are given the smallest FAT type
+        let fat = FatBuilder::new().path_pairs(&path_pairs);
+        assert!(fat.partition_size().unwrap() < 64 * 1024);
+        for &fat_type in &[FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let fat = FatBuilder::new().path_pairs(&path_pairs).fat_type(fat_type);
+            let mut image = io::Cursor::new(Vec::new());
+            fat.write(&mut image).unwrap();
+            assert_eq!(image.get_ref().len() as u64, fat.partition_size().unwrap());
+            let partition_byte_range = 0..(image.get_ref().len() as u64);
+            let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+            assert_eq!(info.fat_type(), fat_type);
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            let mut output = Vec::new();
+            reader.read("/hello.txt", &mut output).unwrap();
+            assert_eq!(output, b"hello");
+            output.clear();
+            reader.read("/EFI/BOOT/BOOTX64.EFI", &mut output).unwrap();
+            assert_eq!(output, large);
+        }
+    }
+
+    #[test]
+    fn volume_label_and_ids() {
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("volume_label_and_ids", b"hello"),
+            in_disk_image: "/hello.txt".into(),
+        }];
+        for &fat_type in &[FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let fat = FatBuilder::new()
+                .path_pairs(&path_pairs)
+                .fat_type(fat_type)
+                .volume_label("ESP 2")
+                .volume_id(0x1234ABCD)
+                .oem_name("MSWIN4.1");
+            let mut image = io::Cursor::new(Vec::new());
+            fat.write(&mut image).unwrap();
+            let partition_byte_range = 0..(image.get_ref().len() as u64);
+            let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+            assert_eq!(info.volume_label(), "ESP 2");
+            assert_eq!(info.volume_id(), 0x1234ABCD);
+            assert_eq!(info.oem_name(), "MSWIN4.1");
+            // the label is the first entry of the root directory
+            let root_directory_offset = if fat_type == FatType::Fat32 {
+                info.bpb.data_offset()
+            } else {
+                info.bpb.root_directory_offset()
+            } as usize;
+            let label_entry = &image.get_ref()[root_directory_offset..][..32];
+            assert_eq!(&label_entry[0..11], b"ESP 2      ");
+            assert_eq!(label_entry[11], directory_attributes::VOLUME_ID);
+            // but isn't listed as a file
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            match reader.lookup("/").unwrap() {
+                FatFile::Directory(directory) => {
+                    assert_eq!(directory.entries().len(), 1);
+                    assert_eq!(directory.entries()[0].name(), "hello.txt");
+                }
+                FatFile::Normal(_) => panic!("root is not a directory"),
+            }
+        }
+        // without a label, the root directory starts with the long name entry of hello.txt
+        let mut image = io::Cursor::new(Vec::new());
+        FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .write(&mut image)
+            .unwrap();
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let info = fat_info(&mut image, partition_byte_range).unwrap();
+        assert_eq!(info.volume_label(), create::VOLUME_LABEL);
+        let root_directory_offset = info.bpb.root_directory_offset() as usize;
+        assert_eq!(
+            image.get_ref()[root_directory_offset + 11],
+            directory_attributes::LONG_NAME
+        );
+        for invalid_label in &["", "lower", " LEADING", "TWELVE CHARS", "A/B", "A.B"] {
+            let fat = FatBuilder::new().volume_label(invalid_label);
+            assert!(fat.partition_size().is_err(), "{:?}", invalid_label);
+        }
+        for invalid_oem_name in &["NINE CHAR", "TAB\t"] {
+            let fat = FatBuilder::new().oem_name(invalid_oem_name);
+            assert!(
+                fat.write(&mut io::sink()).is_err(),
+                "{:?}",
+                invalid_oem_name
+            );
+        }
+    }
+
+    #[test]
+    fn source_date_epoch() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let source_date_epoch = UNIX_EPOCH + Duration::from_secs(1600000000);
+        let time = create::current_time_from(Some("1600000000")).unwrap();
+        assert_eq!(time, source_date_epoch);
+        let time = create::current_time_from(Some(" 1600000000\n")).unwrap();
+        assert_eq!(time, source_date_epoch);
+        assert!(create::current_time_from(Some("yesterday")).is_err());
+        assert!(create::current_time_from(Some("-1")).is_err());
+        assert!(create::current_time_from(None).unwrap() > source_date_epoch);
+        assert_eq!(
+            create::volume_id_from_time(source_date_epoch),
+            (1600000000u64 * 1000) as u32
+        );
+    }
+
+    #[test]
+    fn timestamps_and_attributes() {
+        use std::time::{Duration, UNIX_EPOCH};
+        let path = std::env::temp_dir().join(format!(
+            "mini_fat_{}_timestamps_and_attributes",
+            std::process::id()
+        ));
+        std::fs::write(&path, b"hello").unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        file.set_modified(modified).unwrap();
+        let mut permissions = file.metadata().unwrap().permissions();
+        permissions.set_readonly(true);
+        file.set_permissions(permissions).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let path_pairs = vec![
+            PathPair {
+                in_local_filesystem: file,
+                in_disk_image: "/EFI/BOOT/hello.txt".into(),
+            },
+            PathPair {
+                in_local_filesystem: temp_file("timestamps_and_attributes_other", b"other"),
+                in_disk_image: "/other.txt".into(),
+            },
+        ];
+        let entry = |fat: FatBuilder, path: &str| {
+            let mut image = io::Cursor::new(Vec::new());
+            fat.write(&mut image).unwrap();
+            let partition_byte_range = 0..(image.get_ref().len() as u64);
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            match reader.lookup(path).unwrap() {
+                FatFile::Normal(entry) => entry,
+                FatFile::Directory(_) => panic!("expected file"),
+            }
+        };
+        // timestamps and the read-only attribute come from the local file
+        let hello = entry(
+            FatBuilder::new().path_pairs(&path_pairs),
+            "/EFI/BOOT/hello.txt",
+        );
+        assert_eq!(
+            hello.modification(),
+            Some(FatDateTime::from_system_time(modified))
+        );
+        assert!(hello.attributes().read_only);
+        assert!(!hello.attributes().hidden);
+        let other = entry(FatBuilder::new().path_pairs(&path_pairs), "/other.txt");
+        assert!(!other.attributes().read_only);
+        // a fixed timestamp overrides the local file's metadata
+        let fixed = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let hello = entry(
+            FatBuilder::new().path_pairs(&path_pairs).timestamp(fixed),
+            "/EFI/BOOT/hello.txt",
+        );
+        let fixed_date_time = FatDateTime::from_system_time(fixed);
+        assert_eq!(hello.creation(), Some(fixed_date_time));
+        assert_eq!(hello.modification(), Some(fixed_date_time));
+        assert_eq!(hello.last_accessed(), Some(fixed_date_time.date));
+        // extra attributes can be given for files and directories
+        let fat = || {
+            FatBuilder::new()
+                .path_pairs(&path_pairs)
+                .hidden("/EFI")
+                .system("/EFI/BOOT/hello.txt")
+                .hidden("/EFI/BOOT/hello.txt")
+        };
+        let hello = entry(fat(), "/EFI/BOOT/hello.txt");
+        assert!(hello.attributes().read_only);
+        assert!(hello.attributes().hidden);
+        assert!(hello.attributes().system);
+        let mut image = io::Cursor::new(Vec::new());
+        fat().write(&mut image).unwrap();
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+        match reader.lookup("/").unwrap() {
+            FatFile::Directory(root) => {
+                let efi = root.find_entry("EFI").unwrap();
+                assert!(efi.attributes().hidden);
+                assert!(efi.attributes().directory);
+            }
+            FatFile::Normal(_) => panic!("root is not a directory"),
+        }
+        assert!(FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .hidden("/missing")
+            .partition_size()
+            .is_err());
+    }
+
+    #[test]
+    fn sector_and_cluster_sizes() {
+        let large = (0..20000).map(|i| i as u8).collect::<Vec<_>>();
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("sector_and_cluster_sizes", &large),
+            in_disk_image: "/EFI/BOOT/BOOTX64.EFI".into(),
+        }];
+        for &(bytes_per_sector, sectors_per_cluster) in &[(512, 1), (512, 8), (4096, 1), (4096, 4)]
+        {
+            let fat = FatBuilder::new()
+                .path_pairs(&path_pairs)
+                .bytes_per_sector(bytes_per_sector)
+                .sectors_per_cluster(sectors_per_cluster);
+            let mut image = io::Cursor::new(Vec::new());
+            fat.write(&mut image).unwrap();
+            assert_eq!(image.get_ref().len() as u64, fat.partition_size().unwrap());
+            assert_eq!(image.get_ref().len() % bytes_per_sector as usize, 0);
+            let partition_byte_range = 0..(image.get_ref().len() as u64);
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            assert_eq!(
+                reader.0.bpb.bytes_per_cluster(),
+                bytes_per_sector as u32 * sectors_per_cluster as u32
+            );
+            let mut output = Vec::new();
+            reader.read("/EFI/BOOT/BOOTX64.EFI", &mut output).unwrap();
+            assert_eq!(output, large);
+        }
+        let invalid_sector_size = FatBuilder::new().bytes_per_sector(1000);
+        assert!(invalid_sector_size.partition_size().is_err());
+        let invalid_cluster_size = FatBuilder::new().sectors_per_cluster(3);
+        assert!(invalid_cluster_size.partition_size().is_err());
+    }
+
+    #[test]
+    fn default_cluster_size() {
+        const MIB: u64 = 1 << 20;
+        const GIB: u64 = 1 << 30;
+        let default = create::default_bytes_per_cluster;
+        assert_eq!(default(None, MIB), 2048);
+        assert_eq!(default(None, 200 * MIB), 4096);
+        assert_eq!(default(Some(FatType::Fat12), 20 * MIB), 8192);
+        assert_eq!(default(None, 600 * MIB), 4096);
+        assert_eq!(default(Some(FatType::Fat32), 100 * MIB), 512);
+        assert_eq!(default(None, 10 * GIB), 8192);
+        assert_eq!(default(None, 20 * GIB), 16384);
+        assert_eq!(default(None, 64 * GIB), 32768);
+    }
+
+    #[test]
+    fn partition_size_and_free_space() {
+        let contents = vec![1; 3000];
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("partition_size_and_free_space", &contents),
+            in_disk_image: "/logs/boot.log".into(),
+        }];
+        let contents_size = FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .partition_size()
+            .unwrap();
+        let sizes = [
+            (PartitionSize::Bytes(1 << 20), None, 1 << 20),
+            (PartitionSize::Bytes(40 << 20), None, 40 << 20),
+            (
+                PartitionSize::Bytes(40 << 20),
+                Some(FatType::Fat32),
+                40 << 20,
+            ),
+            (
+                PartitionSize::FreeBytes(100 << 20),
+                None,
+                contents_size + (100 << 20),
+            ),
+        ];
+        for &(size, fat_type, expected_size) in &sizes {
+            let fat = FatBuilder::new().path_pairs(&path_pairs).size(size);
+            let fat = match fat_type {
+                Some(fat_type) => fat.fat_type(fat_type),
+                None => fat,
+            };
+            let mut image = io::Cursor::new(Vec::new());
+            fat.write(&mut image).unwrap();
+            let size = fat.partition_size().unwrap();
+            assert_eq!(image.get_ref().len() as u64, size);
+            assert!(size <= expected_size && size + 512 > expected_size);
+            let partition_byte_range = 0..size;
+            let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+            let (_, layout) = fat.directory_hierarchy_and_layout().unwrap();
+            assert_eq!(info.num_clusters(), layout.num_data_clusters);
+            if info.fat_type() == FatType::Fat32 {
+                let fs_info = info.fs_info.as_ref().unwrap();
+                // the root directory and "logs" each occupy a cluster
+                let num_used_clusters =
+                    2 + contents.len().div_ceil(layout.bytes_per_cluster() as usize) as u32;
+                assert_eq!(fs_info.free_count, info.num_clusters() - num_used_clusters);
+                assert_eq!(fs_info.next_free, 2 + num_used_clusters);
+            }
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            let mut output = Vec::new();
+            reader.read("/logs/boot.log", &mut output).unwrap();
+            assert_eq!(output, contents);
+        }
+        let percent = FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .size(PartitionSize::FreePercent(50));
+        assert!(percent.partition_size().unwrap() >= contents_size * 3 / 2);
+        let too_small = FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .size(PartitionSize::Bytes(4096));
+        assert!(too_small.partition_size().is_err());
+        let too_small_for_fat32 = FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .fat_type(FatType::Fat32)
+            .size(PartitionSize::Bytes(1 << 20));
+        assert!(too_small_for_fat32.partition_size().is_err());
+    }
+
+    #[test]
+    fn name_matching() {
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("name_matching", b"x"),
+            in_disk_image: "/EFI/BOOT/A Long File Name.efi".into(),
+        }];
+        let mut image = io::Cursor::new(Vec::new());
+        write_partition(&mut image, &path_pairs).unwrap();
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let mut reader = FatReader::new(&mut image, partition_byte_range.clone()).unwrap();
+        for path in &[
+            "/efi/boot/a long file name.EFI",
+            "/EFI/BOOT/ALONGF~1.EFI",
+            "/efi/boot/alongf~1.efi",
+        ] {
+            assert!(reader.lookup(path).is_ok(), "{}", path);
+        }
+        let mut reader = FatReader::new(&mut image, partition_byte_range)
+            .unwrap()
+            .with_name_match(NameMatch::Exact);
+        assert!(reader.lookup("/EFI/BOOT/A Long File Name.efi").is_ok());
+        assert!(reader.lookup("/EFI/BOOT/ALONGF~1.EFI").is_ok());
+        assert!(reader.lookup("/efi/boot/a long file name.efi").is_err());
+    }
+
+    #[test]
+    fn open_and_seek() {
+        use std::io::{Read, Seek, SeekFrom};
+        let contents = (0..5000).map(|i| (i / 7) as u8).collect::<Vec<_>>();
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("seek", &contents),
+            in_disk_image: "/file".into(),
+        }];
+        let mut image = io::Cursor::new(Vec::new());
+        write_partition(&mut image, &path_pairs).unwrap();
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+        let mut file = reader.open("/file").unwrap();
+        let mut buf = [0; 600];
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &contents[1000..1600]);
+        file.seek(SeekFrom::Current(-1500)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &contents[100..700]);
+        file.seek(SeekFrom::End(-10)).unwrap();
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, &contents[4990..]);
+        assert!(file.seek(SeekFrom::Current(-5001)).is_err());
+    }
+
+    #[test]
+    fn write_in_place() {
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("write_in_place", b"hello"),
+            in_disk_image: "/EFI/hello.txt".into(),
+        }];
+        let large = (0..5000).map(|i| (i / 3) as u8).collect::<Vec<_>>();
+        for (fat_type, mut image, partition_byte_range) in
+            images(|| FatBuilder::new().path_pairs(&path_pairs))
+        {
+            let free_count_before = fat_info(&mut image, partition_byte_range.clone())
+                .unwrap()
+                .num_free_clusters_hint();
+            let mut writer = FatWriter::new(&mut image, partition_byte_range.clone()).unwrap();
+            writer
+                .create_file("/efi/large.bin", &mut large.as_slice())
+                .unwrap();
+            writer.create_file("/empty", &mut io::empty()).unwrap();
+            writer.create_dir_all("/a/b/c").unwrap();
+            // enough long names to grow the directory beyond its first cluster
+            for i in 0..100 {
+                let name = format!("/a/b/c/A Long File Name {}.txt", i);
+                writer.create_file(&name, &mut name.as_bytes()).unwrap();
+            }
+            let exists = writer.create_file("/EFI/HELLO.TXT", &mut io::empty());
+            assert!(exists.is_err());
+            assert!(writer.create_dir("/x/y").is_err());
+            assert!(writer.create_dir("/efi/hello.txt/y").is_err());
+            let mut reader = FatReader::new(&mut image, partition_byte_range.clone()).unwrap();
+            let mut output = Vec::new();
+            reader.read("/EFI/hello.txt", &mut output).unwrap();
+            assert_eq!(output, b"hello");
+            output.clear();
+            reader.read("/EFI/large.bin", &mut output).unwrap();
+            assert_eq!(output, large);
+            output.clear();
+            reader.read("/empty", &mut output).unwrap();
+            assert!(output.is_empty());
+            for i in 0..100 {
+                let name = format!("/a/b/c/A Long File Name {}.txt", i);
+                output.clear();
+                reader.read(&name, &mut output).unwrap();
+                assert_eq!(output, name.as_bytes());
+            }
+            let entries = match reader.lookup("/a/b").unwrap() {
+                FatFile::Directory(directory) => directory.entries().to_vec(),
+                _ => panic!("expected directory"),
+            };
+            let names = entries.iter().map(|e| e.name()).collect::<Vec<_>>();
+            assert_eq!(names, vec![".", "..", "c"]);
+            let a = reader.lookup("/a").unwrap();
+            match a {
+                FatFile::Directory(directory) => {
+                    assert_eq!(directory.entries()[1].first_cluster(), 0)
+                }
+                _ => panic!("expected directory"),
+            }
+            let free_count_after = fat_info(&mut image, partition_byte_range)
+                .unwrap()
+                .num_free_clusters_hint();
+            if fat_type == FatType::Fat32 {
+                assert!(free_count_after.unwrap() < free_count_before.unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn remove_in_place() {
+        let large = (0..5000).map(|i| (i / 3) as u8).collect::<Vec<_>>();
+        let path_pairs = vec![
+            PathPair {
+                in_local_filesystem: temp_file("remove_in_place_hello", b"hello"),
+                in_disk_image: "/EFI/hello.txt".into(),
+            },
+            PathPair {
+                in_local_filesystem: temp_file("remove_in_place_large", &large),
+                in_disk_image: "/EFI/BOOT/A Long File Name.efi".into(),
+            },
+        ];
+        for (fat_type, mut image, partition_byte_range) in
+            images(|| FatBuilder::new().path_pairs(&path_pairs).directory("/logs"))
+        {
+            let free_count_before = fat_info(&mut image, partition_byte_range.clone())
+                .unwrap()
+                .num_free_clusters_hint();
+            let mut writer = FatWriter::new(&mut image, partition_byte_range.clone()).unwrap();
+            assert!(writer.remove_file("/EFI/BOOT").is_err());
+            assert!(writer.remove_dir("/EFI/hello.txt").is_err());
+            assert!(writer.remove_dir("/EFI").is_err());
+            assert!(writer.remove_file("/missing").is_err());
+            assert!(writer.remove_dir_all("/").is_err());
+            writer.remove_file("/efi/hello.txt").unwrap();
+            writer.remove_dir("/logs").unwrap();
+            writer.remove_dir_all("/EFI").unwrap();
+            let root_entries = match writer.lookup("/").unwrap() {
+                FatFile::Directory(directory) => directory.entries().to_vec(),
+                _ => panic!("expected directory"),
+            };
+            assert!(root_entries.is_empty());
+            // the freed clusters and directory entries can be reused
+            writer
+                .create_file("/EFI/BOOT/BOOTX64.EFI", &mut large.as_slice())
+                .unwrap_err();
+            writer.create_dir_all("/EFI/BOOT").unwrap();
+            writer
+                .create_file("/EFI/BOOT/BOOTX64.EFI", &mut large.as_slice())
+                .unwrap();
+            writer.remove_dir_all("/EFI").unwrap();
+            let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+            if fat_type == FatType::Fat32 {
+                // only the root directory remains
+                assert_eq!(
+                    info.num_free_clusters_hint().unwrap(),
+                    info.num_clusters() - 1
+                );
+                assert!(info.num_free_clusters_hint() > free_count_before);
+            }
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            assert!(reader.lookup("/EFI").is_err());
+        }
+    }
+
+    #[test]
+    fn rename_in_place() {
+        let path_pairs = vec![
+            PathPair {
+                in_local_filesystem: temp_file("rename_in_place_new", b"new"),
+                in_disk_image: "/EFI/BOOT/BOOTX64.EFI".into(),
+            },
+            PathPair {
+                in_local_filesystem: temp_file("rename_in_place_old", b"old"),
+                in_disk_image: "/EFI/BOOT/BOOTX64.EFI.old".into(),
+            },
+        ];
+        for (_, mut image, partition_byte_range) in images(|| {
+            FatBuilder::new()
+                .path_pairs(&path_pairs)
+                .directory("/vendor/keys")
+        }) {
+            let mut writer = FatWriter::new(&mut image, partition_byte_range.clone()).unwrap();
+            let boot = "/EFI/BOOT/BOOTX64.EFI";
+            let old = "/EFI/BOOT/BOOTX64.EFI.old";
+            assert!(writer.rename(boot, old).is_err());
+            writer.rename(boot, "/EFI/BOOT/tmp").unwrap();
+            writer.rename(old, boot).unwrap();
+            writer.rename("/EFI/BOOT/tmp", old).unwrap();
+            writer.rename("/efi/boot", "/EFI/boot").unwrap();
+            assert!(writer.rename("/EFI", "/EFI/boot/EFI").is_err());
+            assert!(writer.rename("/EFI", "/EFI/boot/missing/EFI").is_err());
+            writer.rename("/vendor/keys", "/EFI/boot/keys").unwrap();
+            writer.rename("/EFI/boot", "/boot").unwrap();
+            let keys_first_cluster = match writer.lookup("/boot").unwrap() {
+                FatFile::Directory(directory) => {
+                    let names = directory.entries().iter().map(|e| e.name());
+                    assert_eq!(
+                        names.collect::<Vec<_>>(),
+                        vec![".", "..", "BOOTX64.EFI", "BOOTX64.EFI.old", "keys"]
+                    );
+                    // "boot" was moved into the root directory
+                    assert_eq!(directory.entries()[1].first_cluster(), 0);
+                    directory.entries()[4].first_cluster()
+                }
+                _ => panic!("expected directory"),
+            };
+            let boot_first_cluster = match writer.lookup("/").unwrap() {
+                FatFile::Directory(directory) => {
+                    let boot = directory.find_entry("boot").unwrap();
+                    // the short name alone can't represent a lower case name
+                    assert_eq!(boot.name(), "boot");
+                    assert_eq!(boot.short_name(), "BOOT");
+                    boot.first_cluster()
+                }
+                _ => panic!("expected directory"),
+            };
+            match writer.lookup("/boot/keys").unwrap() {
+                FatFile::Directory(directory) => {
+                    assert_eq!(directory.entries()[0].first_cluster(), keys_first_cluster);
+                    assert_eq!(directory.entries()[1].first_cluster(), boot_first_cluster);
+                }
+                _ => panic!("expected directory"),
+            }
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            let mut output = Vec::new();
+            reader.read("/boot/BOOTX64.EFI", &mut output).unwrap();
+            assert_eq!(output, b"old");
+            output.clear();
+            reader.read("/boot/BOOTX64.EFI.old", &mut output).unwrap();
+            assert_eq!(output, b"new");
+            assert!(reader.lookup("/EFI").is_ok());
+            assert!(reader.lookup("/vendor/keys").is_err());
+        }
+    }
+
+    #[test]
+    fn write_file_in_place() {
+        use std::io::{Seek, SeekFrom, Write};
+        let contents = (0..3000).map(|i| (i / 7) as u8).collect::<Vec<_>>();
+        let path_pairs = vec![
+            PathPair {
+                in_local_filesystem: temp_file("write_file_in_place_config", &contents),
+                in_disk_image: "/config.txt".into(),
+            },
+            PathPair {
+                in_local_filesystem: temp_file("write_file_in_place_other", b"other"),
+                in_disk_image: "/other.txt".into(),
+            },
+        ];
+        let fixed = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        for (_, mut image, partition_byte_range) in
+            images(|| FatBuilder::new().path_pairs(&path_pairs).timestamp(fixed))
+        {
+            let free_count_before = fat_info(&mut image, partition_byte_range.clone())
+                .unwrap()
+                .num_free_clusters_hint();
+            let mut expected = contents.clone();
+            let mut writer = FatWriter::new(&mut image, partition_byte_range.clone()).unwrap();
+            assert!(writer.open("/").is_err());
+            let mut file = writer.open("/config.txt").unwrap();
+            file.seek(SeekFrom::Start(1000)).unwrap();
+            file.write_all(&[0xAA; 100]).unwrap();
+            expected[1000..1100].copy_from_slice(&[0xAA; 100]);
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(&[0xBB; 5000]).unwrap();
+            expected.extend_from_slice(&[0xBB; 5000]);
+            file.set_len(2000).unwrap();
+            expected.truncate(2000);
+            // writing beyond the end of the file fills the gap with zeroes
+            file.seek(SeekFrom::End(10)).unwrap();
+            file.write_all(b"patched").unwrap();
+            expected.extend_from_slice(&[0; 10]);
+            expected.extend_from_slice(b"patched");
+            assert_eq!(file.file_size() as usize, expected.len());
+            file.flush().unwrap();
+            drop(file);
+            let mut reader = FatReader::new(&mut image, partition_byte_range.clone()).unwrap();
+            let mut output = Vec::new();
+            reader.read("/config.txt", &mut output).unwrap();
+            assert_eq!(output, expected);
+            output.clear();
+            reader.read("/other.txt", &mut output).unwrap();
+            assert_eq!(output, b"other");
+            let entry = match reader.lookup("/config.txt").unwrap() {
+                FatFile::Normal(entry) => entry,
+                _ => panic!("expected file"),
+            };
+            assert!(entry.modification().unwrap().date.year > 2001);
+            let mut writer = FatWriter::new(&mut image, partition_byte_range.clone()).unwrap();
+            let mut file = writer.open("/config.txt").unwrap();
+            file.set_len(0).unwrap();
+            file.set_len(contents.len() as u64).unwrap();
+            file.write_all(&contents[0..100]).unwrap();
+            let mut expected = vec![0; contents.len()];
+            expected[0..100].copy_from_slice(&contents[0..100]);
+            // the directory entry and FsInfo structure are written back when the handle is dropped
+            drop(file);
+            let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+            assert_eq!(info.num_free_clusters_hint(), free_count_before);
+            let mut reader = FatReader::new(&mut image, partition_byte_range).unwrap();
+            let mut output = Vec::new();
+            reader.read("/config.txt", &mut output).unwrap();
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn fat_copies() {
+        let path_pairs = vec![PathPair {
+            in_local_filesystem: temp_file("fat_copies", b"hello"),
+            in_disk_image: "/hello.txt".into(),
+        }];
+        let fat = FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .fat_type(FatType::Fat32)
+            .size(PartitionSize::Bytes(40 << 20));
+        let mut image = io::Cursor::new(Vec::new());
+        fat.write(&mut image).unwrap();
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+        assert_eq!(info.num_fats(), 2);
+        assert!(!info.mirroring_disabled());
+        assert!(fat_mismatches(&mut image, partition_byte_range.clone())
+            .unwrap()
+            .is_empty());
+        // with mirroring enabled, writes update every copy of the FAT
+        let mut writer = FatWriter::new(&mut image, partition_byte_range.clone()).unwrap();
+        writer.create_file("/a.txt", &mut &b"a"[..]).unwrap();
+        assert!(fat_mismatches(&mut image, partition_byte_range.clone())
+            .unwrap()
+            .is_empty());
+        let set_ext_flags = |image: &mut io::Cursor<Vec<u8>>, ext_flags: u16| {
+            let bytes_per_sector = info.bpb.bytes_per_sector as usize;
+            for sector in &[0, info.bpb.bk_boot_sector as usize] {
+                let offset = sector * bytes_per_sector + 40;
+                image.get_mut()[offset..(offset + 2)].copy_from_slice(&ext_flags.to_le_bytes());
+            }
+        };
+        set_ext_flags(&mut image, EXT_FLAGS_MIRRORING_DISABLED | 1);
+        let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+        assert!(info.mirroring_disabled());
+        assert_eq!(info.active_fat(), 1);
+        let mut writer = FatWriter::new(&mut image, partition_byte_range.clone()).unwrap();
+        let b = vec![b'b'; 5000];
+        writer.create_file("/b.txt", &mut b.as_slice()).unwrap();
+        let b_cluster = match writer.lookup("/b.txt").unwrap() {
+            FatFile::Normal(entry) => entry.first_cluster(),
+            _ => panic!("expected file"),
+        };
+        let mismatches = fat_mismatches(&mut image, partition_byte_range.clone()).unwrap();
+        let num_clusters = b.len().div_ceil(info.bpb.bytes_per_cluster() as usize);
+        assert_eq!(mismatches.len(), num_clusters);
+        assert_eq!(mismatches[0].cluster, b_cluster);
+        let end_of_chain = FatType::Fat32.fat_entry_end_of_chain();
+        assert_eq!(mismatches.last().unwrap().entries, vec![0, end_of_chain]);
+        // only the active FAT is read
+        let mut reader = FatReader::new(&mut image, partition_byte_range.clone()).unwrap();
+        let mut output = Vec::new();
+        reader.read("/b.txt", &mut output).unwrap();
+        assert_eq!(output, b);
+        set_ext_flags(&mut image, EXT_FLAGS_MIRRORING_DISABLED);
+        let mut reader = FatReader::new(&mut image, partition_byte_range.clone()).unwrap();
+        assert!(reader.read("/b.txt", &mut output).is_err());
+        set_ext_flags(&mut image, EXT_FLAGS_MIRRORING_DISABLED | 2);
+        assert!(fat_info(&mut image, partition_byte_range).is_err());
+        // a FAT which is too small for the number of clusters
+        let mut image = io::Cursor::new(Vec::new());
+        FatBuilder::new()
+            .path_pairs(&path_pairs)
+            .fat_type(FatType::Fat12)
+            .size(PartitionSize::Bytes(1 << 20))
+            .write(&mut image)
+            .unwrap();
+        image.get_mut()[22..24].copy_from_slice(&1u16.to_le_bytes());
+        let partition_byte_range = 0..(image.get_ref().len() as u64);
+        let info = fat_info(&mut image, partition_byte_range.clone()).unwrap();
+        let fat_size_in_bytes = info.bpb.bytes_per_sector as usize;
+        assert!(
+            fat_entry_bytes(FatType::Fat12, info.bpb.maximum_valid_cluster()).end
+                > fat_size_in_bytes
+        );
+        assert!(fat_mismatches(&mut image, partition_byte_range).is_ok());
+    }
+
+    fn short_entry(main: &str, extension: &str) -> RawDirectoryEntryNormal {
+        RawDirectoryEntryNormal {
+            short_filename_main: main.to_string(),
+            short_filename_extension: extension.to_string(),
+            attributes: directory_attributes::ARCHIVE,
+            creation_time_tenth: 0,
+            creation_time: 0,
+            creation_date: 0,
+            last_accessed_date: 0,
+            first_cluster: 0,
+            write_time: 0,
+            write_date: 0,
+            file_size: 0,
+        }
+    }
+
+    #[test]
+    fn long_name_validation() {
+        let mut raw = Vec::new();
+        let valid = short_entry("LONGFI~1", "TXT");
+        let valid_long_name = RawDirectoryEntryLongName::encode_name(
+            "long file name.txt",
+            valid.short_name_checksum(),
+        );
+        // a stale long name left behind by an implementation which doesn't support long names
+        let stale = short_entry("OTHER", "TXT");
+        raw.extend(RawDirectoryEntryLongName::encode_name(
+            "deleted file.txt",
+            0,
+        ));
+        raw.push(stale.encode());
+        // a sequence missing its middle entry
+        let incomplete = short_entry("ANOTHE~1", "");
+        let incomplete_long_name = RawDirectoryEntryLongName::encode_name(
+            "another rather long file name",
+            incomplete.short_name_checksum(),
+        );
+        raw.push(incomplete_long_name[0]);
+        raw.push(incomplete_long_name[2]);
+        raw.push(incomplete.encode());
+        // an orphaned fragment followed by a valid sequence
+        raw.push(incomplete_long_name[1]);
+        raw.extend(valid_long_name);
+        raw.push(valid.encode());
+        // a long name preceding the volume label doesn't belong to the entry after the label
+        let mut label = short_entry("NO NAME", "");
+        label.attributes = directory_attributes::VOLUME_ID;
+        let plain = short_entry("PLAIN", "");
+        raw.extend(RawDirectoryEntryLongName::encode_name(
+            "volume label",
+            label.short_name_checksum(),
+        ));
+        raw.push(label.encode());
+        raw.push(plain.encode());
+        let directory = Directory::from_contiguous(&raw.concat()).unwrap();
+        let names = directory
+            .entries()
+            .iter()
+            .map(|e| e.name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["OTHER.TXT", "ANOTHE~1", "long file name.txt", "PLAIN"]
+        );
+        assert_eq!(
+            directory.diagnostics(),
+            &[
+                DirectoryDiagnostic::LongNameChecksumMismatch {
+                    short_name: "OTHER.TXT".to_string()
+                },
+                DirectoryDiagnostic::OrphanedLongNameEntries { num_entries: 2 },
+                DirectoryDiagnostic::OrphanedLongNameEntries { num_entries: 1 },
+                DirectoryDiagnostic::OrphanedLongNameEntries { num_entries: 1 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+// Generation of 8.3 short names for long file names, following the basis-name and numeric-tail
+// generation algorithms from the FAT spec.
+use std::collections::HashSet;
+use std::fmt;
+
+const MAIN_MAX_LEN: usize = 8;
+const EXTENSION_MAX_LEN: usize = 3;
+const SPECIAL_CHARS: &str = "$%'-_@~`!(){}^#&";
+const LOSSY_REPLACEMENT: char = '_';
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShortName {
+    pub main: String,
+    pub extension: String,
+}
+
+impl fmt::Display for ShortName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.extension.is_empty() {
+            write!(f, "{}", self.main)
+        } else {
+            write!(f, "{}.{}", self.main, self.extension)
+        }
+    }
+}
+
+struct Basis {
+    short_name: ShortName,
+    needs_numeric_tail: bool,
+}
+
+/// Whether a character may appear in a short name or volume label as-is. Lower case letters are
+/// not allowed, as they are converted to upper case in short names.
+pub fn is_valid_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SPECIAL_CHARS.contains(c)
+}
+
+fn short_name_char(c: char) -> char {
+    if c.is_ascii_alphanumeric() || SPECIAL_CHARS.contains(c) {
+        c.to_ascii_uppercase()
+    } else {
+        LOSSY_REPLACEMENT
+    }
+}
+
+fn basis(long_name: &str) -> Basis {
+    let without_spaces = long_name.chars().filter(|&c| c != ' ').collect::<String>();
+    let without_leading_periods = without_spaces.trim_start_matches('.');
+    let (main, extension) = match without_leading_periods.rfind('.') {
+        Some(i) => (
+            &without_leading_periods[..i],
+            &without_leading_periods[(i + 1)..],
+        ),
+        None => (without_leading_periods, ""),
+    };
+    let mut main = main
+        .chars()
+        .filter(|&c| c != '.')
+        .map(short_name_char)
+        .take(MAIN_MAX_LEN)
+        .collect::<String>();
+    if main.is_empty() {
+        main.push(LOSSY_REPLACEMENT);
+    }
+    let extension = extension
+        .chars()
+        .map(short_name_char)
+        .take(EXTENSION_MAX_LEN)
+        .collect::<String>();
+    let short_name = ShortName { main, extension };
+    // A numeric tail is needed if anything other than case was lost when converting the long name
+    let needs_numeric_tail = short_name.to_string() != long_name.to_ascii_uppercase();
+    Basis {
+        short_name,
+        needs_numeric_tail,
+    }
+}
+
+fn with_numeric_tail(short_name: &ShortName, n: u32) -> ShortName {
+    let tail = format!("~{}", n);
+    let main = short_name
+        .main
+        .chars()
+        .take(MAIN_MAX_LEN - tail.len())
+        .chain(tail.chars())
+        .collect();
+    ShortName {
+        main,
+        extension: short_name.extension.clone(),
+    }
+}
+
+/// Returns a short name for each long name in a directory, such that no two short names are the
+/// same. The long names must be distinct when compared case-insensitively.
+pub fn directory_short_names<'a, I>(long_names: I) -> Vec<ShortName>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let bases = long_names.into_iter().map(basis).collect::<Vec<_>>();
+    // Names which can be stored exactly as short names are reserved before any numeric tails are
+    // generated so they don't have to change.
+    let mut used = bases
+        .iter()
+        .filter(|basis| !basis.needs_numeric_tail)
+        .map(|basis| basis.short_name.clone())
+        .collect::<HashSet<_>>();
+    bases
+        .into_iter()
+        .map(|basis| {
+            if basis.needs_numeric_tail {
+                (1..)
+                    .map(|n| with_numeric_tail(&basis.short_name, n))
+                    .find(|short_name| used.insert(short_name.clone()))
+                    .unwrap()
+            } else {
+                basis.short_name
+            }
+        })
+        .collect()
+}
+
+/// Returns a short name for a long name being added to a directory, which is distinct from the
+/// short names already in use
+pub fn unique_short_name<F>(long_name: &str, is_used: F) -> ShortName
+where
+    F: Fn(&ShortName) -> bool,
+{
+    let basis = basis(long_name);
+    if !basis.needs_numeric_tail && !is_used(&basis.short_name) {
+        return basis.short_name;
+    }
+    (1..)
+        .map(|n| with_numeric_tail(&basis.short_name, n))
+        .find(|short_name| !is_used(short_name))
+        .unwrap()
+}
+
+/// Long name entries are only required if the long name differs from its short name
+pub fn needs_long_name(long_name: &str, short_name: &ShortName) -> bool {
+    short_name.to_string() != long_name
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn short_names(long_names: &[&str]) -> Vec<String> {
+        directory_short_names(long_names.iter().cloned())
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn generation() {
+        assert_eq!(
+            short_names(&[
+                "BOOTX64.EFI",
+                "readme.txt",
+                "Program Files",
+                "Program Data",
+                "archive.tar.gz",
+                ".bashrc",
+                "a+b.c++",
+                "PROGRA~1",
+            ]),
+            vec![
+                "BOOTX64.EFI",
+                "README.TXT",
+                "PROGRA~2",
+                "PROGRA~3",
+                "ARCHIV~1.GZ",
+                "BASHRC~1",
+                "A_B~1.C__",
+                "PROGRA~1",
+            ]
+        );
+    }
+
+    #[test]
+    fn unique() {
+        let used = ["README.TXT", "PROGRA~1"];
+        let unique = |long_name| {
+            unique_short_name(long_name, |short_name| {
+                used.contains(&short_name.to_string().as_str())
+            })
+            .to_string()
+        };
+        assert_eq!(unique("BOOTX64.EFI"), "BOOTX64.EFI");
+        assert_eq!(unique("readme.txt"), "README~1.TXT");
+        assert_eq!(unique("Program Files"), "PROGRA~2");
+    }
+
+    #[test]
+    fn long_name_required() {
+        let short_name = |s| directory_short_names(Some(s)).pop().unwrap();
+        assert!(!needs_long_name("BOOTX64.EFI", &short_name("BOOTX64.EFI")));
+        assert!(needs_long_name("bootx64.efi", &short_name("bootx64.efi")));
+        assert!(needs_long_name("LONGFILENAME", &short_name("LONGFILENAME")));
+    }
+}
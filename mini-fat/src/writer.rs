@@ -0,0 +1,1059 @@
+// Modification of existing FAT partitions in place. Clusters are allocated by scanning the FAT for
+// free entries, and every copy of the FAT is kept up to date along with the FsInfo structure on
+// FAT32 volumes.
+use super::{
+    classify_fat_entry, create, directory_attributes, directory_hierarchy, fat_entry_bytes,
+    fat_entry_of_nth_cluster, handle_read, set_fat_entry_bytes, short_name, Directory,
+    DirectoryEntry, Error, FatDateTime, FatError, FatFile, FatType, FileFatEntry, FsInfo,
+    NameMatch, RawDirectoryEntry, RawDirectoryEntryLongName, RawDirectoryEntryNormal, Traverser,
+    DIRECTORY_ENTRY_BYTES, END_OF_DIRECTORY_PREFIX, FS_INFO_FREE_COUNT_UNKNOWN,
+    UNUSED_ENTRY_PREFIX,
+};
+use std::io;
+use std::ops::Range;
+use std::path::{Component, Path};
+use std::time::SystemTime;
+
+const FS_INFO_FREE_COUNT_OFFSET: u64 = 488;
+
+/// Where the entries of a directory are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DirectoryLocation {
+    /// The fixed-size root directory region of FAT12 and FAT16 volumes
+    RootRegion,
+    Clusters(u32),
+}
+
+/// The raw entries of a directory, along with the clusters they are stored in
+struct RawDirectory {
+    location: DirectoryLocation,
+    raw: Vec<u8>,
+    clusters: Vec<u32>,
+}
+
+impl RawDirectory {
+    fn num_slots(&self) -> usize {
+        self.raw.len() / DIRECTORY_ENTRY_BYTES as usize
+    }
+
+    fn slot(&self, index: usize) -> &[u8] {
+        let start = index * DIRECTORY_ENTRY_BYTES as usize;
+        &self.raw[start..(start + DIRECTORY_ENTRY_BYTES as usize)]
+    }
+
+    /// Finds the first run of `count` consecutive unused entries. Every entry after the end of
+    /// directory marker is unused.
+    fn find_free_slots(&self, count: usize) -> Option<usize> {
+        let mut run_start = 0;
+        for index in 0..self.num_slots() {
+            match self.slot(index)[0] {
+                END_OF_DIRECTORY_PREFIX => {
+                    return if self.num_slots() - run_start >= count {
+                        Some(run_start)
+                    } else {
+                        None
+                    };
+                }
+                UNUSED_ENTRY_PREFIX => {
+                    if index + 1 - run_start >= count {
+                        return Some(run_start);
+                    }
+                }
+                _ => run_start = index + 1,
+            }
+        }
+        None
+    }
+}
+
+/// Adds, removes and renames files and directories in an existing FAT partition
+pub struct FatWriter<'a, H>
+where
+    H: io::Read + io::Write + io::Seek,
+{
+    traverser: Traverser<'a, H>,
+    // only present on FAT32
+    fs_info: Option<FsInfo>,
+    // the cluster from which to start searching for free clusters
+    next_free: u32,
+}
+
+impl<'a, H> FatWriter<'a, H>
+where
+    H: io::Read + io::Write + io::Seek,
+{
+    pub fn new(handle: &'a mut H, partition_byte_range: Range<u64>) -> Result<Self, Error> {
+        let mut traverser = Traverser::new(handle, partition_byte_range.start)?;
+        let fs_info = if traverser.bpb.fat_type() == FatType::Fat32 {
+            let mut buf = Vec::new();
+            let (fs_info, _) = FsInfo::read(
+                &traverser.bpb,
+                traverser.handle,
+                traverser.partition_byte_start,
+                &mut buf,
+            )?;
+            Some(fs_info)
+        } else {
+            None
+        };
+        // the hint is ignored if it's unknown or out of range
+        let next_free = fs_info
+            .as_ref()
+            .map(|fs_info| fs_info.next_free)
+            .filter(|&next_free| {
+                next_free >= 2 && next_free <= traverser.bpb.maximum_valid_cluster()
+            })
+            .unwrap_or(2);
+        traverser.buf.clear();
+        Ok(Self {
+            traverser,
+            fs_info,
+            next_free,
+        })
+    }
+
+    /// Paths are matched case-insensitively by default
+    pub fn with_name_match(mut self, name_match: NameMatch) -> Self {
+        self.traverser.name_match = name_match;
+        self
+    }
+
+    pub fn lookup<P>(&mut self, path: P) -> Result<FatFile, Error>
+    where
+        P: AsRef<Path>,
+    {
+        self.traverser.lookup_path(path)
+    }
+
+    /// Creates a file containing the data read from `contents`. The parent directory must exist.
+    pub fn create_file<P, R>(&mut self, path: P, contents: &mut R) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+        R: io::Read,
+    {
+        let result = self.create_file_inner(path.as_ref(), contents);
+        self.write_fs_info()?;
+        result
+    }
+
+    /// Creates an empty directory. The parent directory must exist.
+    pub fn create_dir<P>(&mut self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let result = self.create_dir_inner(path.as_ref());
+        self.write_fs_info()?;
+        result
+    }
+
+    /// Creates a directory along with any missing parents. Existing directories are left as they
+    /// are.
+    pub fn create_dir_all<P>(&mut self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        match self.resolve_directory(path) {
+            Ok(_) => return Ok(()),
+            Err(e) => match e.downcast_ref::<FatError>() {
+                Some(FatError::NoSuchFile) => (),
+                _ => return Err(e),
+            },
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        self.create_dir(path)
+    }
+
+    pub fn remove_file<P>(&mut self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let result = self.remove_inner(path.as_ref(), false, false);
+        self.write_fs_info()?;
+        result
+    }
+
+    /// Removes an empty directory
+    pub fn remove_dir<P>(&mut self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let result = self.remove_inner(path.as_ref(), true, false);
+        self.write_fs_info()?;
+        result
+    }
+
+    /// Opens a file for writing. The file's cluster chain grows and shrinks as it is written and
+    /// truncated. Its directory entry and the FsInfo structure are updated when the handle is
+    /// flushed or dropped, and by `set_len`.
+    pub fn open<P>(&mut self, path: P) -> Result<FatFileWriter<'_, 'a, H>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let (directory, entry) = self.existing_entry(path.as_ref())?;
+        if entry.is_directory() {
+            return Err(FatError::ExpectedFileFoundDirectory.into());
+        }
+        let clusters = if entry.first_cluster == 0 {
+            Vec::new()
+        } else {
+            self.cluster_chain(entry.first_cluster)?
+        };
+        let bytes_per_cluster = self.traverser.bpb.bytes_per_cluster() as u64;
+        if (clusters.len() as u64) < (entry.file_size as u64).div_ceil(bytes_per_cluster) {
+            return Err(FatError::ClusterChainTooShort.into());
+        }
+        let entry_byte_offset = self.slot_byte_offset(&directory, entry.slots.end - 1);
+        Ok(FatFileWriter {
+            writer: self,
+            entry_byte_offset,
+            file_size: entry.file_size,
+            clusters,
+            position: 0,
+            modified: None,
+        })
+    }
+
+    /// Renames a file or directory, moving it to another directory if necessary. Its contents
+    /// aren't copied. The destination must not already exist, unless it only differs from the
+    /// current name in case.
+    pub fn rename<P, Q>(&mut self, from: P, to: Q) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let result = self.rename_inner(from.as_ref(), to.as_ref());
+        self.write_fs_info()?;
+        result
+    }
+
+    /// Removes a directory along with everything it contains
+    pub fn remove_dir_all<P>(&mut self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let result = self.remove_inner(path.as_ref(), true, true);
+        self.write_fs_info()?;
+        result
+    }
+
+    fn create_file_inner<R>(&mut self, path: &Path, contents: &mut R) -> Result<(), Error>
+    where
+        R: io::Read,
+    {
+        let (parent, name) = self.parent_and_new_name(path)?;
+        let mut first_cluster = 0;
+        let result = self
+            .write_cluster_chain(contents, &mut first_cluster)
+            .and_then(|file_size| {
+                self.add_entry(
+                    parent,
+                    name,
+                    directory_attributes::ARCHIVE,
+                    first_cluster,
+                    file_size,
+                )
+            });
+        if result.is_err() && first_cluster != 0 {
+            self.free_cluster_chain(first_cluster)?;
+        }
+        result
+    }
+
+    fn create_dir_inner(&mut self, path: &Path) -> Result<(), Error> {
+        let (parent, name) = self.parent_and_new_name(path)?;
+        let cluster = self.allocate_cluster(None)?;
+        let result = self.write_dot_entries(cluster, parent).and_then(|()| {
+            self.add_entry(parent, name, directory_attributes::DIRECTORY, cluster, 0)
+        });
+        if result.is_err() {
+            self.free_cluster_chain(cluster)?;
+        }
+        result
+    }
+
+    fn rename_inner(&mut self, from: &Path, to: &Path) -> Result<(), Error> {
+        let (source, entry) = self.existing_entry(from)?;
+        let name = final_name(to)?;
+        directory_hierarchy::validate_long_name(name)?;
+        let destination = self.resolve_directory(to.parent().unwrap_or_else(|| Path::new("/")))?;
+        let same_directory = destination == source.location;
+        let destination_directory = self.read_directory(destination)?;
+        // an entry may be renamed to a name which differs from its current name only in case
+        if let Some(existing) =
+            Directory::from_contiguous(&destination_directory.raw)?.find_entry(name)
+        {
+            if !(same_directory && existing.slots == entry.slots) {
+                return Err(FatError::FileExists.into());
+            }
+        }
+        let moves_directory = entry.is_directory() && !same_directory;
+        if moves_directory {
+            self.check_not_within(destination, entry.first_cluster)?;
+        }
+        let normal_entry = match RawDirectoryEntry::parse(source.slot(entry.slots.end - 1))? {
+            RawDirectoryEntry::Normal { entry, .. } => entry,
+            RawDirectoryEntry::LongName(_) => unreachable!("slots end with a short entry"),
+        };
+        // The old entries are removed first so that their slots and short name can be reused.
+        // They are restored if the new entries can't be written.
+        let mut original_slots = Vec::new();
+        for slot in entry.slots.clone() {
+            let offset = self.slot_byte_offset(&source, slot);
+            original_slots.push((offset, source.slot(slot).to_vec()));
+            self.write_at(offset, &[UNUSED_ENTRY_PREFIX])?;
+        }
+        if let Err(e) = self.insert_entry(destination, name, normal_entry) {
+            for (offset, raw_entry) in original_slots {
+                self.write_at(offset, &raw_entry)?;
+            }
+            return Err(e);
+        }
+        if moves_directory {
+            self.set_dot_dot_cluster(entry.first_cluster, destination)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a directory being moved isn't the destination or one of its ancestors, by
+    /// following ".." entries from the destination up to the root directory
+    fn check_not_within(
+        &mut self,
+        destination: DirectoryLocation,
+        directory_cluster: u32,
+    ) -> Result<(), Error> {
+        let mut visited = Vec::new();
+        let mut location = destination;
+        while let DirectoryLocation::Clusters(cluster) = location {
+            if location == self.root_location() {
+                break;
+            }
+            if cluster == directory_cluster {
+                return Err(FatError::DirectoryMovedIntoItself.into());
+            }
+            if visited.contains(&cluster) {
+                return Err(FatError::CyclicClusterChain.into());
+            }
+            visited.push(cluster);
+            let directory = self.read_directory(location)?;
+            let parent_cluster = Directory::from_contiguous(&directory.raw)?
+                .entries
+                .iter()
+                .find(|entry| entry.short_name == "..")
+                .map(|entry| entry.first_cluster)
+                .ok_or(FatError::NoSuchFile)?;
+            location = self.directory_location(parent_cluster);
+        }
+        Ok(())
+    }
+
+    /// Points the ".." entry of a directory at its new parent
+    fn set_dot_dot_cluster(
+        &mut self,
+        directory_cluster: u32,
+        parent: DirectoryLocation,
+    ) -> Result<(), Error> {
+        let directory_cluster = self.traverser.allocated_cluster(directory_cluster)?;
+        let offset =
+            self.traverser.cluster_byte_offset(directory_cluster) + DIRECTORY_ENTRY_BYTES as u64;
+        let mut raw_entry = Vec::new();
+        handle_read(
+            self.traverser.handle,
+            offset,
+            DIRECTORY_ENTRY_BYTES as usize,
+            &mut raw_entry,
+        )?;
+        if &raw_entry[0..11] != b"..         " {
+            // there is no ".." entry to update
+            return Ok(());
+        }
+        let parent_cluster = self.dot_dot_cluster(parent);
+        raw_entry[20..22].copy_from_slice(&((parent_cluster >> 16) as u16).to_le_bytes());
+        raw_entry[26..28].copy_from_slice(&(parent_cluster as u16).to_le_bytes());
+        self.write_at(offset, &raw_entry)
+    }
+
+    fn remove_inner(
+        &mut self,
+        path: &Path,
+        expect_directory: bool,
+        recursive: bool,
+    ) -> Result<(), Error> {
+        let (parent, entry) = self.existing_entry(path)?;
+        match (expect_directory, entry.is_directory()) {
+            (false, true) => return Err(FatError::ExpectedFileFoundDirectory.into()),
+            (true, false) => return Err(FatError::ExpectedDirectoryFoundFile.into()),
+            _ => (),
+        }
+        if entry.is_directory()
+            && !recursive
+            && !self.directory_children(entry.first_cluster)?.is_empty()
+        {
+            return Err(FatError::DirectoryNotEmpty.into());
+        }
+        for slot in entry.slots.clone() {
+            let offset = self.slot_byte_offset(&parent, slot);
+            self.write_at(offset, &[UNUSED_ENTRY_PREFIX])?;
+        }
+        self.free_entry_clusters(&entry)
+    }
+
+    /// Frees the clusters of a file, or of a directory and everything it contains. A directory's
+    /// own clusters are freed before its children are visited, so a directory which contains
+    /// one of its ancestors results in an error rather than endless recursion.
+    fn free_entry_clusters(&mut self, entry: &DirectoryEntry) -> Result<(), Error> {
+        if entry.is_directory() {
+            let children = self.directory_children(entry.first_cluster)?;
+            self.free_cluster_chain(entry.first_cluster)?;
+            for child in &children {
+                self.free_entry_clusters(child)?;
+            }
+        } else if entry.first_cluster != 0 {
+            self.free_cluster_chain(entry.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    /// The entries of a directory other than "." and ".."
+    fn directory_children(&mut self, first_cluster: u32) -> Result<Vec<DirectoryEntry>, Error> {
+        let first_cluster = self.traverser.allocated_cluster(first_cluster)?;
+        let directory = self.read_directory(DirectoryLocation::Clusters(first_cluster))?;
+        let mut entries = Directory::from_contiguous(&directory.raw)?.entries;
+        entries.retain(|entry| entry.short_name != "." && entry.short_name != "..");
+        Ok(entries)
+    }
+
+    /// Zeroes the first cluster of a new directory, then adds its "." and ".." entries
+    fn write_dot_entries(&mut self, cluster: u32, parent: DirectoryLocation) -> Result<(), Error> {
+        self.zero_cluster(cluster)?;
+        let timestamps = directory_hierarchy::Timestamps::all(create::current_time()?);
+        let mut raw = directory_hierarchy::dot_entry_raw(".", cluster, &timestamps).to_vec();
+        raw.extend_from_slice(&directory_hierarchy::dot_entry_raw(
+            "..",
+            self.dot_dot_cluster(parent),
+            &timestamps,
+        ));
+        let offset = self.traverser.cluster_byte_offset(cluster);
+        self.write_at(offset, &raw)
+    }
+
+    /// ".." entries refer to the root directory with cluster 0, even on FAT32
+    fn dot_dot_cluster(&self, parent: DirectoryLocation) -> u32 {
+        match parent {
+            DirectoryLocation::RootRegion => 0,
+            DirectoryLocation::Clusters(cluster) if cluster == self.traverser.bpb.root_cluster => 0,
+            DirectoryLocation::Clusters(cluster) => cluster,
+        }
+    }
+
+    fn root_location(&self) -> DirectoryLocation {
+        match self.traverser.bpb.fat_type() {
+            FatType::Fat32 => DirectoryLocation::Clusters(self.traverser.bpb.root_cluster),
+            FatType::Fat12 | FatType::Fat16 => DirectoryLocation::RootRegion,
+        }
+    }
+
+    fn directory_location(&self, first_cluster: u32) -> DirectoryLocation {
+        if first_cluster == 0 {
+            self.root_location()
+        } else {
+            DirectoryLocation::Clusters(first_cluster)
+        }
+    }
+
+    fn resolve_directory(&mut self, path: &Path) -> Result<DirectoryLocation, Error> {
+        let mut location_stack = vec![self.root_location()];
+        for component in path.components() {
+            match component {
+                Component::Prefix(_) => return Err(FatError::NoSuchFile.into()),
+                Component::CurDir => (),
+                Component::ParentDir => {
+                    location_stack.pop();
+                    if location_stack.is_empty() {
+                        return Err(FatError::InvalidPath.into());
+                    }
+                }
+                Component::RootDir => location_stack.truncate(1),
+                Component::Normal(os_str) => {
+                    let location = *location_stack.last().unwrap();
+                    let directory = self.read_directory(location)?;
+                    let directory = Directory::from_contiguous(&directory.raw)?;
+                    let entry = directory
+                        .find_entry_with_name_match(
+                            os_str.to_string_lossy().as_ref(),
+                            self.traverser.name_match,
+                        )
+                        .ok_or(FatError::NoSuchFile)?;
+                    if !entry.is_directory() {
+                        return Err(FatError::ExpectedDirectoryFoundFile.into());
+                    }
+                    location_stack.push(self.directory_location(entry.first_cluster));
+                }
+            }
+        }
+        Ok(location_stack.pop().unwrap())
+    }
+
+    /// Splits a path into its parent directory and the name of a new entry in that directory,
+    /// checking that no entry already has that name
+    fn parent_and_new_name<'p>(
+        &mut self,
+        path: &'p Path,
+    ) -> Result<(DirectoryLocation, &'p str), Error> {
+        let name = final_name(path)?;
+        directory_hierarchy::validate_long_name(name)?;
+        let parent = self.resolve_directory(path.parent().unwrap_or_else(|| Path::new("/")))?;
+        let directory = self.read_directory(parent)?;
+        // FAT file names are case-insensitive regardless of how paths are matched
+        if Directory::from_contiguous(&directory.raw)?
+            .find_entry(name)
+            .is_some()
+        {
+            return Err(FatError::FileExists.into());
+        }
+        Ok((parent, name))
+    }
+
+    /// Finds the entry a path refers to, along with the directory containing it
+    fn existing_entry(&mut self, path: &Path) -> Result<(RawDirectory, DirectoryEntry), Error> {
+        let name = final_name(path)?;
+        let parent = self.resolve_directory(path.parent().unwrap_or_else(|| Path::new("/")))?;
+        let directory = self.read_directory(parent)?;
+        let entry = Directory::from_contiguous(&directory.raw)?
+            .find_entry_with_name_match(name, self.traverser.name_match)
+            .cloned()
+            .ok_or(FatError::NoSuchFile)?;
+        Ok((directory, entry))
+    }
+
+    fn cluster_chain(&mut self, first_cluster: u32) -> Result<Vec<u32>, Error> {
+        let fat_type = self.traverser.bpb.fat_type();
+        let maximum_valid_cluster = self.traverser.bpb.maximum_valid_cluster();
+        let mut clusters = Vec::new();
+        let mut entry = first_cluster;
+        loop {
+            match classify_fat_entry(fat_type, entry, maximum_valid_cluster)
+                .map_err(FatError::FatLookup)?
+            {
+                FileFatEntry::EndOfFile => break Ok(clusters),
+                FileFatEntry::AllocatedCluster(cluster) => {
+                    if clusters.len() as u32 >= maximum_valid_cluster {
+                        break Err(FatError::CyclicClusterChain.into());
+                    }
+                    clusters.push(cluster);
+                    entry = self.fat_entry(cluster)?;
+                }
+            }
+        }
+    }
+
+    fn read_directory(&mut self, location: DirectoryLocation) -> Result<RawDirectory, Error> {
+        let (raw, clusters) = match location {
+            DirectoryLocation::RootRegion => {
+                let mut raw = Vec::new();
+                handle_read(
+                    self.traverser.handle,
+                    self.traverser.partition_byte_start
+                        + self.traverser.bpb.root_directory_offset(),
+                    self.traverser.bpb.root_directory_size(),
+                    &mut raw,
+                )?;
+                (raw, Vec::new())
+            }
+            DirectoryLocation::Clusters(first_cluster) => {
+                let clusters = self.cluster_chain(first_cluster)?;
+                let bytes_per_cluster = self.traverser.bpb.bytes_per_cluster() as usize;
+                let mut raw = Vec::with_capacity(clusters.len() * bytes_per_cluster);
+                let mut buf = Vec::new();
+                for &cluster in &clusters {
+                    let offset = self.traverser.cluster_byte_offset(cluster);
+                    handle_read(self.traverser.handle, offset, bytes_per_cluster, &mut buf)?;
+                    raw.extend_from_slice(&buf);
+                }
+                (raw, clusters)
+            }
+        };
+        Ok(RawDirectory {
+            location,
+            raw,
+            clusters,
+        })
+    }
+
+    fn slot_byte_offset(&self, directory: &RawDirectory, index: usize) -> u64 {
+        let byte_index = index as u64 * DIRECTORY_ENTRY_BYTES as u64;
+        match directory.location {
+            DirectoryLocation::RootRegion => {
+                self.traverser.partition_byte_start
+                    + self.traverser.bpb.root_directory_offset()
+                    + byte_index
+            }
+            DirectoryLocation::Clusters(_) => {
+                let bytes_per_cluster = self.traverser.bpb.bytes_per_cluster() as u64;
+                let cluster = directory.clusters[(byte_index / bytes_per_cluster) as usize];
+                self.traverser.cluster_byte_offset(cluster) + byte_index % bytes_per_cluster
+            }
+        }
+    }
+
+    /// Adds a cluster to the end of a directory. The root directory of FAT12 and FAT16 volumes
+    /// has a fixed size and can't grow.
+    fn grow_directory(&mut self, directory: &mut RawDirectory) -> Result<(), Error> {
+        if directory.location == DirectoryLocation::RootRegion {
+            return Err(FatError::RootDirectoryFull.into());
+        }
+        let cluster = self.allocate_cluster(directory.clusters.last().cloned())?;
+        self.zero_cluster(cluster)?;
+        directory.clusters.push(cluster);
+        let bytes_per_cluster = self.traverser.bpb.bytes_per_cluster() as usize;
+        directory
+            .raw
+            .resize(directory.raw.len() + bytes_per_cluster, 0);
+        Ok(())
+    }
+
+    /// Writes a short entry, preceded by long name entries if necessary, to the first unused
+    /// entries of a directory which can hold them
+    fn add_entry(
+        &mut self,
+        location: DirectoryLocation,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+        file_size: u32,
+    ) -> Result<(), Error> {
+        let timestamps = directory_hierarchy::Timestamps::all(create::current_time()?);
+        let normal_entry = timestamps.entry_raw(
+            String::new(),
+            String::new(),
+            attributes,
+            first_cluster,
+            file_size,
+        );
+        self.insert_entry(location, name, normal_entry)
+    }
+
+    /// Gives a short entry a short name which is unique within a directory, then writes it,
+    /// preceded by long name entries if necessary, to the first unused entries which can hold
+    /// them
+    fn insert_entry(
+        &mut self,
+        location: DirectoryLocation,
+        name: &str,
+        mut normal_entry: RawDirectoryEntryNormal,
+    ) -> Result<(), Error> {
+        let mut directory = self.read_directory(location)?;
+        let existing = Directory::from_contiguous(&directory.raw)?;
+        let short_name = short_name::unique_short_name(name, |short_name| {
+            let short_name = short_name.to_string();
+            existing
+                .entries()
+                .iter()
+                .any(|entry| entry.short_name() == short_name)
+        });
+        let needs_long_name = short_name::needs_long_name(name, &short_name);
+        normal_entry.short_filename_main = short_name.main;
+        normal_entry.short_filename_extension = short_name.extension;
+        let mut raw_entries = if needs_long_name {
+            RawDirectoryEntryLongName::encode_name(name, normal_entry.short_name_checksum())
+        } else {
+            Vec::new()
+        };
+        raw_entries.push(normal_entry.encode());
+        let first_slot = loop {
+            match directory.find_free_slots(raw_entries.len()) {
+                Some(first_slot) => break first_slot,
+                None => self.grow_directory(&mut directory)?,
+            }
+        };
+        for (i, raw_entry) in raw_entries.iter().enumerate() {
+            let offset = self.slot_byte_offset(&directory, first_slot + i);
+            self.write_at(offset, raw_entry)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the data read from `contents` to newly allocated clusters, returning its size.
+    /// `first_cluster` is set as soon as the first cluster is allocated, so the chain can be
+    /// freed if an error occurs partway through.
+    fn write_cluster_chain<R>(
+        &mut self,
+        contents: &mut R,
+        first_cluster: &mut u32,
+    ) -> Result<u32, Error>
+    where
+        R: io::Read,
+    {
+        let bytes_per_cluster = self.traverser.bpb.bytes_per_cluster() as usize;
+        let mut buf = vec![0; bytes_per_cluster];
+        let mut previous_cluster = None;
+        let mut size = 0u64;
+        loop {
+            let len = read_up_to(contents, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            size += len as u64;
+            if size > u32::MAX as u64 {
+                return Err(FatError::FileTooLarge(size).into());
+            }
+            // the remainder of the final cluster is zeroed
+            buf[len..].iter_mut().for_each(|byte| *byte = 0);
+            let cluster = self.allocate_cluster(previous_cluster)?;
+            if previous_cluster.is_none() {
+                *first_cluster = cluster;
+            }
+            let offset = self.traverser.cluster_byte_offset(cluster);
+            self.write_at(offset, &buf)?;
+            previous_cluster = Some(cluster);
+            if len < bytes_per_cluster {
+                break;
+            }
+        }
+        Ok(size as u32)
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, Error> {
+        fat_entry_of_nth_cluster(
+            self.traverser.handle,
+            self.traverser.bpb.fat_type(),
+            self.traverser.partition_byte_start + self.traverser.bpb.fat_offset(),
+            cluster,
+        )
+    }
+
+    /// Updates an entry in every copy of the FAT, or only in the active FAT if mirroring is
+    /// disabled
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error> {
+        let bpb = &self.traverser.bpb;
+        let fat_type = bpb.fat_type();
+        let entry_bytes = fat_entry_bytes(fat_type, cluster);
+        let offsets = bpb
+            .fats_to_update()
+            .map(|i| {
+                self.traverser.partition_byte_start
+                    + bpb.fat_copy_offset(i)
+                    + entry_bytes.start as u64
+            })
+            .collect::<Vec<_>>();
+        let mut buf = vec![0; entry_bytes.len()];
+        for offset in offsets {
+            handle_read(self.traverser.handle, offset, buf.len(), &mut buf)?;
+            set_fat_entry_bytes(&mut buf, fat_type, cluster, value);
+            self.write_at(offset, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Finds a free cluster, starting from the cluster after the one most recently allocated, and
+    /// marks it as the end of a chain. If `previous_cluster` is given, the new cluster is
+    /// appended to its chain.
+    fn allocate_cluster(&mut self, previous_cluster: Option<u32>) -> Result<u32, Error> {
+        let maximum_valid_cluster = self.traverser.bpb.maximum_valid_cluster();
+        let num_clusters = maximum_valid_cluster - 1;
+        let start = self.next_free - 2;
+        for i in 0..num_clusters {
+            let cluster = 2 + (start + i) % num_clusters;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            let end_of_chain = self.traverser.bpb.fat_type().fat_entry_end_of_chain();
+            self.set_fat_entry(cluster, end_of_chain)?;
+            if let Some(previous_cluster) = previous_cluster {
+                self.set_fat_entry(previous_cluster, cluster)?;
+            }
+            self.next_free = if cluster < maximum_valid_cluster {
+                cluster + 1
+            } else {
+                2
+            };
+            if let Some(ref mut fs_info) = self.fs_info {
+                if fs_info.free_count != FS_INFO_FREE_COUNT_UNKNOWN {
+                    fs_info.free_count = fs_info.free_count.saturating_sub(1);
+                }
+                fs_info.next_free = self.next_free;
+            }
+            return Ok(cluster);
+        }
+        Err(FatError::NoFreeClusters.into())
+    }
+
+    fn free_cluster_chain(&mut self, first_cluster: u32) -> Result<(), Error> {
+        let clusters = self.cluster_chain(first_cluster)?;
+        self.free_clusters(&clusters)
+    }
+
+    fn free_clusters(&mut self, clusters: &[u32]) -> Result<(), Error> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, 0)?;
+            if let Some(ref mut fs_info) = self.fs_info {
+                if fs_info.free_count != FS_INFO_FREE_COUNT_UNKNOWN {
+                    fs_info.free_count += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> Result<(), Error> {
+        let offset = self.traverser.cluster_byte_offset(cluster);
+        let zeros = vec![0; self.traverser.bpb.bytes_per_cluster() as usize];
+        self.write_at(offset, &zeros)
+    }
+
+    /// Writes the free count and next free cluster to the FsInfo structure and its backup
+    fn write_fs_info(&mut self) -> Result<(), Error> {
+        let fs_info = match self.fs_info {
+            Some(ref fs_info) => fs_info,
+            None => return Ok(()),
+        };
+        let mut raw = fs_info.free_count.to_le_bytes().to_vec();
+        raw.extend_from_slice(&fs_info.next_free.to_le_bytes());
+        let bpb = &self.traverser.bpb;
+        let mut sectors = vec![bpb.fs_info];
+        if bpb.bk_boot_sector != 0 {
+            sectors.push(bpb.bk_boot_sector + bpb.fs_info);
+        }
+        for sector in sectors {
+            let offset = self.traverser.partition_byte_start
+                + sector as u64 * self.traverser.bpb.bytes_per_sector as u64
+                + FS_INFO_FREE_COUNT_OFFSET;
+            self.write_at(offset, &raw)?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.traverser.handle.seek(io::SeekFrom::Start(offset))?;
+        self.traverser.handle.write_all(data)?;
+        Ok(())
+    }
+}
+
+pub struct FatFileWriter<'w, 'a, H>
+where
+    H: io::Read + io::Write + io::Seek,
+{
+    writer: &'w mut FatWriter<'a, H>,
+    // the position of the file's short entry within the partition
+    entry_byte_offset: u64,
+    file_size: u32,
+    clusters: Vec<u32>,
+    position: u64,
+    // when the file was last changed, if its directory entry hasn't been updated since
+    modified: Option<SystemTime>,
+}
+
+impl<'w, 'a, H> FatFileWriter<'w, 'a, H>
+where
+    H: io::Read + io::Write + io::Seek,
+{
+    pub fn file_size(&self) -> u32 {
+        self.file_size
+    }
+
+    /// Truncates or extends the file. Extending the file fills the new part with zeroes.
+    pub fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        let result = self.set_len_inner(size);
+        self.write_back()?;
+        result
+    }
+
+    fn set_len_inner(&mut self, size: u64) -> Result<(), Error> {
+        if size > u32::MAX as u64 {
+            return Err(FatError::FileTooLarge(size).into());
+        }
+        let bytes_per_cluster = self.writer.traverser.bpb.bytes_per_cluster() as u64;
+        let num_clusters = size.div_ceil(bytes_per_cluster) as usize;
+        if size < self.file_size as u64 {
+            self.truncate_clusters(num_clusters)?;
+        } else if size > self.file_size as u64 {
+            // the remainder of the final cluster may contain stale data
+            let allocated_size = self.clusters.len() as u64 * bytes_per_cluster;
+            let zeroed_end = size.min(allocated_size);
+            if zeroed_end > self.file_size as u64 {
+                let cluster = self.clusters[(self.file_size as u64 / bytes_per_cluster) as usize];
+                let offset_within_cluster = self.file_size as u64 % bytes_per_cluster;
+                let offset =
+                    self.writer.traverser.cluster_byte_offset(cluster) + offset_within_cluster;
+                let zeros = vec![0; (zeroed_end - self.file_size as u64) as usize];
+                self.writer.write_at(offset, &zeros)?;
+            }
+            self.extend_clusters(num_clusters)?;
+        }
+        self.file_size = size as u32;
+        self.modified = Some(create::current_time()?);
+        Ok(())
+    }
+
+    /// Appends zeroed clusters until the chain has `num_clusters` clusters. If the volume fills
+    /// up partway through, the chain is restored to its original length.
+    fn extend_clusters(&mut self, num_clusters: usize) -> Result<(), Error> {
+        let original_num_clusters = self.clusters.len();
+        while self.clusters.len() < num_clusters {
+            let result = self
+                .writer
+                .allocate_cluster(self.clusters.last().cloned())
+                .and_then(|cluster| {
+                    self.clusters.push(cluster);
+                    self.writer.zero_cluster(cluster)
+                });
+            if let Err(e) = result {
+                self.truncate_clusters(original_num_clusters)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate_clusters(&mut self, num_clusters: usize) -> Result<(), Error> {
+        if num_clusters >= self.clusters.len() {
+            return Ok(());
+        }
+        let freed = self.clusters.split_off(num_clusters);
+        if let Some(&last) = self.clusters.last() {
+            let end_of_chain = self
+                .writer
+                .traverser
+                .bpb
+                .fat_type()
+                .fat_entry_end_of_chain();
+            self.writer.set_fat_entry(last, end_of_chain)?;
+        }
+        self.writer.free_clusters(&freed)
+    }
+
+    /// Records the file's size, first cluster and modification time in its directory entry if
+    /// it has changed, then writes the free cluster count to the FsInfo structure
+    fn write_back(&mut self) -> Result<(), Error> {
+        if let Some(modified) = self.modified {
+            self.update_entry(modified)?;
+            self.modified = None;
+        }
+        self.writer.write_fs_info()
+    }
+
+    fn update_entry(&mut self, modified: SystemTime) -> Result<(), Error> {
+        let first_cluster = self.clusters.first().cloned().unwrap_or(0);
+        let write = FatDateTime::from_system_time(modified);
+        let mut raw_entry = Vec::new();
+        handle_read(
+            self.writer.traverser.handle,
+            self.entry_byte_offset,
+            DIRECTORY_ENTRY_BYTES as usize,
+            &mut raw_entry,
+        )?;
+        raw_entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        raw_entry[22..24].copy_from_slice(&write.time.raw().to_le_bytes());
+        raw_entry[24..26].copy_from_slice(&write.date.raw().to_le_bytes());
+        raw_entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        raw_entry[28..32].copy_from_slice(&self.file_size.to_le_bytes());
+        self.writer.write_at(self.entry_byte_offset, &raw_entry)
+    }
+
+    fn write_at_position(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = self.position + buf.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FatError::FileTooLarge(end).into());
+        }
+        if self.position > self.file_size as u64 {
+            // writing past the end of the file leaves a gap of zeroes
+            self.set_len_inner(self.position)?;
+        }
+        let bytes_per_cluster = self.writer.traverser.bpb.bytes_per_cluster() as u64;
+        let offset_within_cluster = self.position % bytes_per_cluster;
+        // writes don't cross cluster boundaries as consecutive clusters needn't be contiguous
+        let len = (buf.len() as u64).min(bytes_per_cluster - offset_within_cluster);
+        let end = self.position + len;
+        self.extend_clusters(end.div_ceil(bytes_per_cluster) as usize)?;
+        let cluster = self.clusters[(self.position / bytes_per_cluster) as usize];
+        let offset = self.writer.traverser.cluster_byte_offset(cluster) + offset_within_cluster;
+        self.writer.write_at(offset, &buf[0..(len as usize)])?;
+        self.position = end;
+        self.file_size = self.file_size.max(end as u32);
+        self.modified = Some(create::current_time()?);
+        Ok(len as usize)
+    }
+}
+
+impl<'w, 'a, H> io::Write for FatFileWriter<'w, 'a, H>
+where
+    H: io::Read + io::Write + io::Seek,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_at_position(buf).map_err(io::Error::other)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_back().map_err(io::Error::other)?;
+        self.writer.traverser.handle.flush()
+    }
+}
+
+impl<'w, 'a, H> Drop for FatFileWriter<'w, 'a, H>
+where
+    H: io::Read + io::Write + io::Seek,
+{
+    fn drop(&mut self) {
+        // errors can't be reported here, so callers which need to handle them should flush first
+        let _ = self.write_back();
+    }
+}
+
+impl<'w, 'a, H> io::Seek for FatFileWriter<'w, 'a, H>
+where
+    H: io::Read + io::Write + io::Seek,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => (self.file_size as u64).checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// The name of the file or directory a path refers to
+fn final_name(path: &Path) -> Result<&str, Error> {
+    match path.components().next_back() {
+        Some(Component::Normal(os_str)) => os_str.to_str().ok_or_else(|| {
+            FatError::InvalidDiskPath(
+                "disk image paths must consist of utf-8 characters".to_string(),
+            )
+            .into()
+        }),
+        _ => Err(FatError::InvalidDiskPath(
+            "path must end with a file or directory name".to_string(),
+        )
+        .into()),
+    }
+}
+
+/// Reads until `buf` is full or the end of the input is reached
+fn read_up_to<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error>
+where
+    R: io::Read,
+{
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(len)
+}
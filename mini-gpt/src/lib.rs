@@ -1,13 +1,18 @@
 pub use anyhow::Error;
 use std::fmt;
 use std::io;
-use std::ops::Range;
-use uuid::Uuid;
+use std::ops::{Range, RangeInclusive};
+pub use uuid::Uuid;
 
 mod crc32;
 
+pub use guid::guid_from_seed;
+
 mod guid {
-    use uuid::Uuid;
+    use uuid::{Builder, Uuid, Variant, Version};
+
+    const FNV_OFFSET_BASIS_128: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const FNV_PRIME_128: u128 = 0x0000000001000000000000000000013b;
 
     pub fn guid_to_uuid(guid: u128) -> Uuid {
         let d1 = guid as u32;
@@ -25,6 +30,19 @@ mod guid {
             | ((u64::from_le_bytes(*d4) as u128) << 64)
     }
 
+    /// A random-looking (version 4) GUID derived from a seed, for creating reproducible disk
+    /// images. The same seed always produces the same GUID. The seed is hashed with 128-bit
+    /// FNV-1a, so this is not suitable where GUIDs must be unpredictable.
+    pub fn guid_from_seed(seed: &[u8]) -> Uuid {
+        let hash = seed.iter().fold(FNV_OFFSET_BASIS_128, |hash, &byte| {
+            (hash ^ byte as u128).wrapping_mul(FNV_PRIME_128)
+        });
+        Builder::from_bytes(hash.to_be_bytes())
+            .set_variant(Variant::RFC4122)
+            .set_version(Version::Random)
+            .build()
+    }
+
     #[cfg(test)]
     mod test {
         #[test]
@@ -34,6 +52,14 @@ mod guid {
             let uuid_round_tripped = super::guid_to_uuid(guid);
             assert_eq!(uuid, uuid_round_tripped);
         }
+
+        #[test]
+        fn from_seed() {
+            let a = super::guid_from_seed(b"a");
+            assert_eq!(a, super::guid_from_seed(b"a"));
+            assert_ne!(a, super::guid_from_seed(b"b"));
+            assert_eq!(a.get_version(), Some(uuid::Version::Random));
+        }
     }
 }
 
@@ -51,6 +77,9 @@ pub enum GptError {
     NoPartitions,
     InvalidMbrSignature(u16),
     BackupPartitionArrayDoesNotMatch,
+    EmptyPartition(String),
+    UnsupportedLogicalBlockSize(u64),
+    PartitionContentsTooLarge,
 }
 
 impl fmt::Display for GptError {
@@ -63,9 +92,6 @@ impl std::error::Error for GptError {}
 
 #[derive(Debug)]
 struct GptHeader {
-    revision: u32,
-    header_size: u32,
-    header_crc32: u32,
     my_lba: u64,
     alternate_lba: u64,
     first_usable_lba: u64,
@@ -77,24 +103,42 @@ struct GptHeader {
     partition_entry_array_crc32: u32,
 }
 
-const LOGICAL_BLOCK_SIZE: usize = 512;
+pub const DEFAULT_LOGICAL_BLOCK_SIZE: u64 = 512;
+/// Logical block sizes which can be read and created. When reading a disk, the logical block size
+/// is detected by looking for a GPT header at the start of the second logical block.
+pub const SUPPORTED_LOGICAL_BLOCK_SIZES: &[u64] = &[512, 4096];
 const REQUIRED_SIGNATURE: u64 = 0x5452415020494645;
 const THIS_REVISION: u32 = 0x10000;
 const MIN_HEADER_SIZE: u32 = 92;
 
+#[derive(Debug, Clone, Copy)]
+enum HeaderLocation {
+    Primary,
+    Backup,
+}
+
 impl GptHeader {
-    fn new_single_partition_raw(
-        disk_size_in_lba: u64,
+    fn new_raw(
+        layout: &create::Layout,
         disk_guid: Uuid,
         partition_entry_array_raw: &[u8],
-    ) -> [u8; LOGICAL_BLOCK_SIZE as usize] {
-        let my_lba: u64 = 1;
-        let alternate_lba: u64 = disk_size_in_lba - 1;
-        let first_usable_lba: u64 = 2 // mbr and primary gpt header
-                + create::PARTITION_ARRAY_NUM_LBA;
-        let last_usable_lba: u64 = disk_size_in_lba - 1 - create::PARTITION_ARRAY_NUM_LBA - 1;
-        let partition_entry_lba: u64 = 2; // mbr and primary gpt header
-        let mut raw = [0; LOGICAL_BLOCK_SIZE as usize];
+        location: HeaderLocation,
+    ) -> Vec<u8> {
+        let (my_lba, alternate_lba, partition_entry_lba) = match location {
+            HeaderLocation::Primary => (
+                layout.primary_header_lba(),
+                layout.backup_header_lba(),
+                layout.primary_partition_entry_lba(),
+            ),
+            HeaderLocation::Backup => (
+                layout.backup_header_lba(),
+                layout.primary_header_lba(),
+                layout.backup_partition_entry_lba(),
+            ),
+        };
+        let first_usable_lba = layout.first_usable_lba();
+        let last_usable_lba = layout.last_usable_lba();
+        let mut raw = vec![0; layout.logical_block_size as usize];
         raw[0..8].copy_from_slice(&REQUIRED_SIGNATURE.to_le_bytes());
         raw[8..12].copy_from_slice(&THIS_REVISION.to_le_bytes());
         raw[12..16].copy_from_slice(&MIN_HEADER_SIZE.to_le_bytes());
@@ -106,7 +150,7 @@ impl GptHeader {
         raw[48..56].copy_from_slice(&last_usable_lba.to_le_bytes());
         raw[56..72].copy_from_slice(&guid::uuid_to_guid(disk_guid).to_le_bytes());
         raw[72..80].copy_from_slice(&partition_entry_lba.to_le_bytes());
-        raw[80..84].copy_from_slice(&create::NUMBER_OF_PARTITION_ENTRIES.to_le_bytes());
+        raw[80..84].copy_from_slice(&layout.number_of_partition_entries.to_le_bytes());
         raw[84..88].copy_from_slice(&create::SIZE_OF_PARTITION_ENTRY.to_le_bytes());
         raw[88..92].copy_from_slice(&crc32::crc32(partition_entry_array_raw).to_le_bytes());
         let header_crc32 = crc32::crc32(&raw[0..(MIN_HEADER_SIZE as usize)]);
@@ -125,7 +169,7 @@ impl GptHeader {
             return Err(GptError::IncorrectRevision(revision).into());
         }
         let header_size = u32::from_le_bytes(raw[12..16].try_into().unwrap());
-        if header_size < MIN_HEADER_SIZE || header_size as usize > LOGICAL_BLOCK_SIZE {
+        if header_size < MIN_HEADER_SIZE || header_size as usize > raw.len() {
             return Err(GptError::InvalidHeaderSize(header_size).into());
         }
         let header_crc32 = u32::from_le_bytes(raw[16..20].try_into().unwrap());
@@ -155,9 +199,6 @@ impl GptHeader {
             }
         }
         Ok(Self {
-            revision,
-            header_size,
-            header_crc32,
             my_lba,
             alternate_lba,
             first_usable_lba,
@@ -171,16 +212,15 @@ impl GptHeader {
     }
 
     fn crc32_from_logical_block(logical_block: &[u8], header_size: u32) -> u32 {
-        let mut copy = [0; LOGICAL_BLOCK_SIZE];
+        let mut copy = vec![0; header_size as usize];
         copy[0..header_size as usize].copy_from_slice(&logical_block[0..(header_size as usize)]);
         // zero-out the crc field of the copy
         copy[16..20].copy_from_slice(&0u32.to_le_bytes());
         crc32::crc32(&copy[0..(header_size as usize)])
     }
 
-    fn partition_entry_array_byte_range(&self) -> Range<u64> {
-        let partition_entry_array_start_index =
-            self.partition_entry_lba * LOGICAL_BLOCK_SIZE as u64;
+    fn partition_entry_array_byte_range(&self, logical_block_size: u64) -> Range<u64> {
+        let partition_entry_array_start_index = self.partition_entry_lba * logical_block_size;
         let partition_entry_array_size =
             self.size_of_partition_entry * self.number_of_partition_entries;
         partition_entry_array_start_index
@@ -189,8 +229,13 @@ impl GptHeader {
 
     fn compare_header_and_backup_header(header: &Self, backup: &Self) -> Result<(), Error> {
         if header.my_lba == backup.alternate_lba
-            || header.alternate_lba == backup.my_lba
-            || header.disk_guid == backup.disk_guid
+            && header.alternate_lba == backup.my_lba
+            && header.first_usable_lba == backup.first_usable_lba
+            && header.last_usable_lba == backup.last_usable_lba
+            && header.disk_guid == backup.disk_guid
+            && header.number_of_partition_entries == backup.number_of_partition_entries
+            && header.size_of_partition_entry == backup.size_of_partition_entry
+            && header.partition_entry_array_crc32 == backup.partition_entry_array_crc32
         {
             Ok(())
         } else {
@@ -209,59 +254,61 @@ struct PartitionEntry {
     partition_name: String,
 }
 
-const PARITION_TYPE_GUID_EFI_SYSTEM_PARTITION_STR: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+/// Type GUIDs of commonly used partition types
+pub mod partition_type_guid {
+    pub const EFI_SYSTEM_PARTITION: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+    pub const LINUX_FILESYSTEM_DATA: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+    pub const LINUX_ROOT_X86_64: &str = "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709";
+    pub const LINUX_ROOT_AARCH64: &str = "B921B045-1DF0-41C3-AF44-4C6F280D3FAE";
+    pub const LINUX_SWAP: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F";
+    pub const MICROSOFT_BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+}
 
-mod gpt_partition_attributes {
+/// Bit indices of partition attributes defined by the UEFI spec
+pub mod partition_attributes {
     pub const REQUIRED_PARTITION: u8 = 0;
+    pub const NO_BLOCK_IO_PROTOCOL: u8 = 1;
+    pub const LEGACY_BIOS_BOOTABLE: u8 = 2;
 }
 
 impl PartitionEntry {
-    fn new_first_partition_with_size_in_lba_raw(
-        partition_size_in_lba: u64,
-        unique_partition_guid: Uuid,
-        partition_name: String,
+    fn new_raw(
+        partition: &NewPartition,
+        lba_range: &Range<u64>,
     ) -> [u8; create::SIZE_OF_PARTITION_ENTRY as usize] {
-        let starting_lba: u64 = 2 // mbr and primary gpt header
-                + create::PARTITION_ARRAY_NUM_LBA;
-        let ending_lba: u64 = starting_lba + partition_size_in_lba - 1;
-        let attributes: u64 = 1 << gpt_partition_attributes::REQUIRED_PARTITION;
         let max_num_chars = (128 - 56) / 2;
-        let partition_name_encoded = partition_name
+        let partition_name_encoded = partition
+            .partition_name
             .encode_utf16()
             .take(max_num_chars)
-            .flat_map(|c| c.to_le_bytes().iter().cloned().collect::<Vec<_>>())
+            .flat_map(|c| c.to_le_bytes().to_vec())
             .collect::<Vec<_>>();
         debug_assert!(partition_name_encoded.len() <= (128 - 56));
         let mut raw = [0; create::SIZE_OF_PARTITION_ENTRY as usize];
-        raw[0..16].copy_from_slice(
-            &guid::uuid_to_guid(
-                Uuid::parse_str(PARITION_TYPE_GUID_EFI_SYSTEM_PARTITION_STR).unwrap(),
-            )
-            .to_le_bytes(),
-        );
-        raw[16..32].copy_from_slice(&guid::uuid_to_guid(unique_partition_guid).to_le_bytes());
-        raw[32..40].copy_from_slice(&starting_lba.to_le_bytes());
-        raw[40..48].copy_from_slice(&ending_lba.to_le_bytes());
-        raw[48..56].copy_from_slice(&attributes.to_le_bytes());
+        raw[0..16]
+            .copy_from_slice(&guid::uuid_to_guid(partition.partition_type_guid).to_le_bytes());
+        raw[16..32]
+            .copy_from_slice(&guid::uuid_to_guid(partition.unique_partition_guid).to_le_bytes());
+        raw[32..40].copy_from_slice(&lba_range.start.to_le_bytes());
+        raw[40..48].copy_from_slice(&(lba_range.end - 1).to_le_bytes()); // ending lba is inclusive
+        raw[48..56].copy_from_slice(&partition.attributes.to_le_bytes());
         raw[56..(56 + partition_name_encoded.len())].copy_from_slice(&partition_name_encoded);
         raw
     }
 
-    fn new_array_single_partition_raw(
-        partition_size_in_lba: u64,
-        unique_partition_guid: Uuid,
-        partition_name: String,
-    ) -> [u8; create::SIZE_OF_PARTITION_ENTRY as usize * create::NUMBER_OF_PARTITION_ENTRIES as usize]
-    {
-        let mut raw = [0; create::SIZE_OF_PARTITION_ENTRY as usize
-            * create::NUMBER_OF_PARTITION_ENTRIES as usize];
-        raw[0..create::SIZE_OF_PARTITION_ENTRY as usize].copy_from_slice(
-            &Self::new_first_partition_with_size_in_lba_raw(
-                partition_size_in_lba,
-                unique_partition_guid,
-                partition_name,
-            ),
-        );
+    fn new_array_raw(partitions: &[NewPartition], layout: &create::Layout) -> Vec<u8> {
+        let mut raw = vec![
+            0;
+            create::SIZE_OF_PARTITION_ENTRY as usize
+                * layout.number_of_partition_entries as usize
+        ];
+        for ((partition, lba_range), raw_entry) in partitions
+            .iter()
+            .zip(layout.partition_lba_ranges.iter())
+            .zip(raw.chunks_mut(create::SIZE_OF_PARTITION_ENTRY as usize))
+        {
+            raw_entry.copy_from_slice(&Self::new_raw(partition, lba_range));
+        }
         raw
     }
 
@@ -307,13 +354,55 @@ impl PartitionEntry {
         }
     }
 
-    fn partition_byte_range(&self) -> Range<u64> {
-        (self.starting_lba as u64 * LOGICAL_BLOCK_SIZE as u64)
-            ..((self.ending_lba as u64 + 1) * LOGICAL_BLOCK_SIZE as u64)
+    fn partition_byte_range(&self, logical_block_size: u64) -> Range<u64> {
+        (self.starting_lba * logical_block_size)..((self.ending_lba + 1) * logical_block_size)
+    }
+
+    fn is_used(&self) -> bool {
+        // unused entries have a partition type guid of zero
+        !self.partition_type_guid.is_nil()
+    }
+}
+
+/// A used entry of the partition entry array
+#[derive(Debug, Clone, Copy)]
+pub struct Partition<'a> {
+    index: usize,
+    entry: &'a PartitionEntry,
+    logical_block_size: u64,
+}
+
+impl<'a> Partition<'a> {
+    /// The index of the partition's entry in the partition entry array
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    pub fn partition_type_guid(&self) -> Uuid {
+        self.entry.partition_type_guid
+    }
+    pub fn unique_partition_guid(&self) -> Uuid {
+        self.entry.unique_partition_guid
+    }
+    pub fn starting_lba(&self) -> u64 {
+        self.entry.starting_lba
+    }
+    /// The last logical block of the partition (inclusive)
+    pub fn ending_lba(&self) -> u64 {
+        self.entry.ending_lba
+    }
+    pub fn attributes(&self) -> u64 {
+        self.entry.attributes
+    }
+    pub fn name(&self) -> &'a str {
+        self.entry.partition_name.as_str()
+    }
+    pub fn byte_range(&self) -> Range<u64> {
+        self.entry.partition_byte_range(self.logical_block_size)
     }
 }
 
 mod mbr {
+    pub const SIZE: usize = 512;
     pub const BOOT_CODE_SIZE: usize = 440;
     pub const PARTITION_RECORD_COUNT: usize = 4;
     pub const REQUIRED_SIGNATURE: u16 = 0xAA55;
@@ -363,7 +452,10 @@ struct MbrPartitionRecord {
 }
 
 impl MbrPartitionRecord {
-    fn new_protective_with_disk_size_in_lba(disk_size_in_lba: u64) -> Self {
+    fn new_protective_with_disk_size_in_lba(
+        disk_size_in_lba: u64,
+        logical_block_size: u64,
+    ) -> Self {
         Self {
             boot_indicator: 0,
             starting_chs: 512,
@@ -371,19 +463,25 @@ impl MbrPartitionRecord {
             os_type: mbr::OS_TYPE_GPT_PROTECTIVE,
             size_in_lba: (disk_size_in_lba - 1).min(mbr::PARTITION_RECORD_MAX_SIZE_IN_LBA as u64)
                 as u32,
-            ending_chs: (disk_size_in_lba * LOGICAL_BLOCK_SIZE as u64 - 1)
+            ending_chs: (disk_size_in_lba * logical_block_size - 1)
                 .min(mbr::PARTITION_RECORD_MAX_ENDING_CHS as u64) as u32,
         }
     }
 }
 
 impl Mbr {
-    fn new_protective_with_disk_size_in_lba(disk_size_in_lba: u64) -> Self {
+    fn new_protective_with_disk_size_in_lba(
+        disk_size_in_lba: u64,
+        logical_block_size: u64,
+    ) -> Self {
         Self {
             boot_code: BootCode::default(),
             unique_mbr_disk_signature: 0,
             partition_record: [
-                MbrPartitionRecord::new_protective_with_disk_size_in_lba(disk_size_in_lba),
+                MbrPartitionRecord::new_protective_with_disk_size_in_lba(
+                    disk_size_in_lba,
+                    logical_block_size,
+                ),
                 MbrPartitionRecord::default(),
                 MbrPartitionRecord::default(),
                 MbrPartitionRecord::default(),
@@ -391,27 +489,27 @@ impl Mbr {
             signature: mbr::REQUIRED_SIGNATURE,
         }
     }
-    fn encode(&self) -> [u8; LOGICAL_BLOCK_SIZE] {
-        let mut encoded = [0; LOGICAL_BLOCK_SIZE];
-        (&mut encoded[0..mbr::BOOT_CODE_SIZE]).copy_from_slice(&self.boot_code.raw);
-        (&mut encoded[mbr::UNIQUE_MBR_SIGNATURE_OFFSET..(mbr::UNIQUE_MBR_SIGNATURE_OFFSET + 4)])
+    fn encode(&self) -> [u8; mbr::SIZE] {
+        let mut encoded = [0; mbr::SIZE];
+        encoded[0..mbr::BOOT_CODE_SIZE].copy_from_slice(&self.boot_code.raw);
+        encoded[mbr::UNIQUE_MBR_SIGNATURE_OFFSET..(mbr::UNIQUE_MBR_SIGNATURE_OFFSET + 4)]
             .copy_from_slice(&self.unique_mbr_disk_signature.to_le_bytes());
         for (i, partition_record) in self.partition_record.iter().enumerate() {
             let base_index = mbr::PARTITION_RECORD_OFFSET + (mbr::PARTITION_RECORD_SIZE * i);
             let partition_record_encoded =
                 &mut encoded[base_index..(base_index + mbr::PARTITION_RECORD_SIZE)];
             partition_record_encoded[0] = partition_record.boot_indicator;
-            (&mut partition_record_encoded[1..4])
+            partition_record_encoded[1..4]
                 .copy_from_slice(&partition_record.starting_chs.to_le_bytes()[0..3]);
             partition_record_encoded[4] = partition_record.os_type;
-            (&mut partition_record_encoded[5..8])
+            partition_record_encoded[5..8]
                 .copy_from_slice(&partition_record.ending_chs.to_le_bytes()[0..3]);
-            (&mut partition_record_encoded[8..12])
+            partition_record_encoded[8..12]
                 .copy_from_slice(&partition_record.starting_lba.to_le_bytes());
-            (&mut partition_record_encoded[12..16])
+            partition_record_encoded[12..16]
                 .copy_from_slice(&partition_record.size_in_lba.to_le_bytes());
         }
-        (&mut encoded[mbr::SIGNATURE_OFFSET..mbr::SIGNATURE_OFFSET + 2])
+        encoded[mbr::SIGNATURE_OFFSET..mbr::SIGNATURE_OFFSET + 2]
             .copy_from_slice(&self.signature.to_le_bytes()[0..2]);
         encoded
     }
@@ -429,10 +527,10 @@ impl Mbr {
         );
         let partition_record = {
             let mut partition_record = [MbrPartitionRecord::default(); mbr::PARTITION_RECORD_COUNT];
-            for i in 0..mbr::PARTITION_RECORD_COUNT {
+            for (i, partition_record) in partition_record.iter_mut().enumerate() {
                 let base = mbr::PARTITION_RECORD_OFFSET + i * mbr::PARTITION_RECORD_SIZE;
                 let partition_record_bytes = &raw[base..(base + mbr::PARTITION_RECORD_SIZE)];
-                partition_record[i] = MbrPartitionRecord {
+                *partition_record = MbrPartitionRecord {
                     boot_indicator: partition_record_bytes[0],
                     starting_chs: partition_record_bytes[1] as u32
                         | ((partition_record_bytes[2] as u32) << 8)
@@ -480,100 +578,175 @@ where
 
 #[derive(Debug)]
 struct GptInfoBackupHeader {
-    header: GptHeader,
     comparison: Result<(), Error>,
+    partition_entry_array_comparison: Result<(), Error>,
 }
 
 #[derive(Debug)]
 pub struct GptInfo {
-    mbr: Mbr,
     header: GptHeader,
     backup_header: Result<GptInfoBackupHeader, Error>,
     partition_entry_array: Vec<PartitionEntry>,
+    logical_block_size: u64,
 }
 
 impl GptInfo {
+    pub fn logical_block_size(&self) -> u64 {
+        self.logical_block_size
+    }
+
+    pub fn disk_guid(&self) -> Uuid {
+        self.header.disk_guid
+    }
+
+    /// The range of logical blocks which may be used by partitions
+    pub fn usable_lba_range(&self) -> RangeInclusive<u64> {
+        self.header.first_usable_lba..=self.header.last_usable_lba
+    }
+
+    /// The reason the backup GPT header or backup partition entry array couldn't be read or
+    /// doesn't match its primary counterpart, if there is one
+    pub fn backup_header_error(&self) -> Option<&Error> {
+        match self.backup_header {
+            Ok(ref backup_header) => backup_header
+                .comparison
+                .as_ref()
+                .and(backup_header.partition_entry_array_comparison.as_ref())
+                .err(),
+            Err(ref e) => Some(e),
+        }
+    }
+
+    /// Iterates over the used entries of the partition entry array
+    pub fn partitions(&self) -> impl '_ + Iterator<Item = Partition<'_>> {
+        self.partition_entry_array
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_used())
+            .map(move |(index, entry)| Partition {
+                index,
+                entry,
+                logical_block_size: self.logical_block_size,
+            })
+    }
+
+    /// The partition at the given index of the partition entry array, if that entry is used
+    pub fn partition(&self, index: usize) -> Option<Partition<'_>> {
+        self.partitions().find(|p| p.index() == index)
+    }
+
+    pub fn partition_by_name(&self, name: &str) -> Option<Partition<'_>> {
+        self.partitions().find(|p| p.name() == name)
+    }
+
+    pub fn partition_by_unique_guid(&self, unique_partition_guid: Uuid) -> Option<Partition<'_>> {
+        self.partitions()
+            .find(|p| p.unique_partition_guid() == unique_partition_guid)
+    }
+
     pub fn first_partition_byte_range(&self) -> Result<Range<u64>, Error> {
-        let first_partition_entry = self
-            .partition_entry_array
-            .first()
-            .ok_or(GptError::NoPartitions)?;
-        Ok(first_partition_entry.partition_byte_range())
+        let first_partition = self.partitions().next().ok_or(GptError::NoPartitions)?;
+        Ok(first_partition.byte_range())
+    }
+}
+
+fn detect_logical_block_size<H>(handle: &mut H, buf: &mut Vec<u8>) -> Result<u64, Error>
+where
+    H: io::Seek + io::Read,
+{
+    use std::convert::TryInto;
+    let mut first_signature = None;
+    for &logical_block_size in SUPPORTED_LOGICAL_BLOCK_SIZES {
+        // the primary gpt header is in the second logical block and begins with a signature
+        if let Err(e) = handle_read(handle, logical_block_size, 8, buf) {
+            match e.downcast_ref::<io::Error>() {
+                // a disk too small to contain a second logical block of this size can't use it
+                Some(io_error) if io_error.kind() == io::ErrorKind::UnexpectedEof => continue,
+                _ => return Err(e),
+            }
+        }
+        let signature = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+        if signature == REQUIRED_SIGNATURE {
+            return Ok(logical_block_size);
+        }
+        first_signature.get_or_insert(signature);
     }
+    Err(GptError::InvalidSignature(first_signature.unwrap_or(0)).into())
 }
 
 pub fn gpt_info<H>(handle: &mut H) -> Result<GptInfo, Error>
 where
     H: io::Seek + io::Read,
 {
-    let mut buf = vec![0; LOGICAL_BLOCK_SIZE];
+    let mut buf = Vec::new();
     // read the mbr
-    handle_read(
-        handle,
-        0 * LOGICAL_BLOCK_SIZE as u64,
-        LOGICAL_BLOCK_SIZE,
-        &mut buf,
-    )?;
-    let mbr = Mbr::parse(&buf)?;
+    handle_read(handle, 0, mbr::SIZE, &mut buf)?;
+    // the protective mbr is only checked for its signature
+    Mbr::parse(&buf)?;
+    let logical_block_size = detect_logical_block_size(handle, &mut buf)?;
     // read the gpt header
     handle_read(
         handle,
-        1 * LOGICAL_BLOCK_SIZE as u64,
-        LOGICAL_BLOCK_SIZE,
+        logical_block_size,
+        logical_block_size as usize,
         &mut buf,
     )?;
     let header = GptHeader::parse(&buf)?;
     if header.my_lba != 1 {
         return Err(GptError::UnexpectedMyLba(header.my_lba).into());
     }
+    // read the partition entry array
+    let partition_entry_array_byte_range =
+        header.partition_entry_array_byte_range(logical_block_size);
+    handle_read(
+        handle,
+        partition_entry_array_byte_range.start,
+        (partition_entry_array_byte_range.end - partition_entry_array_byte_range.start) as usize,
+        &mut buf,
+    )?;
+    let partition_entry_array = PartitionEntry::parse_array(&buf, &header)?.collect::<Vec<_>>();
+    // problems with the backup gpt are recorded rather than preventing the disk from being read
     let backup_header = {
         // read the backup gpt header
         handle_read(
             handle,
-            header.alternate_lba * LOGICAL_BLOCK_SIZE as u64,
-            LOGICAL_BLOCK_SIZE,
+            header.alternate_lba * logical_block_size,
+            logical_block_size as usize,
             &mut buf,
         )
         .and_then(|()| GptHeader::parse(&buf))
         .map(|backup_header| {
             let comparison = GptHeader::compare_header_and_backup_header(&header, &backup_header);
+            // read the backup partition entry array
+            let backup_partition_entry_array_byte_range =
+                backup_header.partition_entry_array_byte_range(logical_block_size);
+            let partition_entry_array_comparison = handle_read(
+                handle,
+                backup_partition_entry_array_byte_range.start,
+                (backup_partition_entry_array_byte_range.end
+                    - backup_partition_entry_array_byte_range.start) as usize,
+                &mut buf,
+            )
+            .and_then(|()| {
+                let backup_partition_entry_array =
+                    PartitionEntry::parse_array(&buf, &backup_header)?.collect::<Vec<_>>();
+                if backup_partition_entry_array == partition_entry_array {
+                    Ok(())
+                } else {
+                    Err(GptError::BackupPartitionArrayDoesNotMatch.into())
+                }
+            });
             GptInfoBackupHeader {
-                header: backup_header,
                 comparison,
+                partition_entry_array_comparison,
             }
         })
     };
-    // read the partition entry array
-    let partition_entry_array_byte_range = header.partition_entry_array_byte_range();
-    handle_read(
-        handle,
-        partition_entry_array_byte_range.start,
-        (partition_entry_array_byte_range.end - partition_entry_array_byte_range.start) as usize,
-        &mut buf,
-    )?;
-    let partition_entry_array = PartitionEntry::parse_array(&buf, &header)?.collect::<Vec<_>>();
-    if let Ok(ref backup_header) = backup_header {
-        // read the backup partition entry array
-        let backup_partition_entry_array_byte_range =
-            backup_header.header.partition_entry_array_byte_range();
-        handle_read(
-            handle,
-            backup_partition_entry_array_byte_range.start,
-            (backup_partition_entry_array_byte_range.end
-                - backup_partition_entry_array_byte_range.start) as usize,
-            &mut buf,
-        )?;
-        let backup_partition_entry_array =
-            PartitionEntry::parse_array(&buf, &header)?.collect::<Vec<_>>();
-        if backup_partition_entry_array != partition_entry_array {
-            return Err(GptError::BackupPartitionArrayDoesNotMatch.into());
-        }
-    }
     Ok(GptInfo {
-        mbr,
         header,
         backup_header,
         partition_entry_array,
+        logical_block_size,
     })
 }
 
@@ -584,74 +757,384 @@ where
     gpt_info(handle)?.first_partition_byte_range()
 }
 
-const fn size_in_bytes_to_num_logical_blocks(size: u64) -> u64 {
-    ((size - 1) / LOGICAL_BLOCK_SIZE as u64) + 1
+fn size_in_bytes_to_num_logical_blocks(size: u64, logical_block_size: u64) -> u64 {
+    ((size - 1) / logical_block_size) + 1
 }
 
 mod create {
-    pub const NUMBER_OF_PARTITION_ENTRIES: u32 = 4;
+    use std::ops::Range;
+
+    /// The UEFI specification requires at least 16KiB to be reserved for the partition entry
+    /// array, regardless of how many partitions are used
+    pub const MIN_NUMBER_OF_PARTITION_ENTRIES: u32 = 128;
     pub const SIZE_OF_PARTITION_ENTRY: u32 = 128;
-    pub const PARTITION_ARRAY_NUM_LBA: u64 = super::size_in_bytes_to_num_logical_blocks(
-        NUMBER_OF_PARTITION_ENTRIES as u64 * SIZE_OF_PARTITION_ENTRY as u64,
-    );
-}
-
-fn disk_size_in_lba(partition_size_in_lba: u64) -> u64 {
-    // The disk must be large enough to contain the following:
-    // - mbr (1 LB)
-    // - primary gpt header (1 LB)
-    // - primary partition entry array
-    // - partition
-    // - backup partition entry array
-    // - backup gpt header (1 LB)
-    //
-    1 // mbr
-        + 1 // primary gpt header
-        + create::PARTITION_ARRAY_NUM_LBA // primary partition array
-        + partition_size_in_lba
-        + create::PARTITION_ARRAY_NUM_LBA // backup primary array
-        + 1 // backup gpt header
+
+    /// The location of each part of a disk being created, in logical blocks
+    #[derive(Debug)]
+    pub struct Layout {
+        pub logical_block_size: u64,
+        pub number_of_partition_entries: u32,
+        pub partition_array_num_lba: u64,
+        pub partition_lba_ranges: Vec<Range<u64>>,
+        pub disk_size_in_lba: u64,
+    }
+
+    impl Layout {
+        pub fn new<I>(logical_block_size: u64, partition_sizes_in_lba: I) -> Self
+        where
+            I: IntoIterator<Item = u64>,
+        {
+            // The disk must be large enough to contain the following:
+            // - mbr (1 LB)
+            // - primary gpt header (1 LB)
+            // - primary partition entry array
+            // - partitions
+            // - backup partition entry array
+            // - backup gpt header (1 LB)
+            let partition_sizes_in_lba = partition_sizes_in_lba.into_iter().collect::<Vec<_>>();
+            let number_of_partition_entries =
+                (partition_sizes_in_lba.len() as u32).max(MIN_NUMBER_OF_PARTITION_ENTRIES);
+            let partition_array_num_lba = super::size_in_bytes_to_num_logical_blocks(
+                number_of_partition_entries as u64 * SIZE_OF_PARTITION_ENTRY as u64,
+                logical_block_size,
+            );
+            let mut next_lba = 2 + partition_array_num_lba; // mbr and primary gpt header
+            let partition_lba_ranges = partition_sizes_in_lba
+                .into_iter()
+                .map(|size_in_lba| {
+                    let start = next_lba;
+                    next_lba += size_in_lba;
+                    start..next_lba
+                })
+                .collect();
+            let disk_size_in_lba = next_lba
+                + partition_array_num_lba // backup partition array
+                + 1; // backup gpt header
+            Self {
+                logical_block_size,
+                number_of_partition_entries,
+                partition_array_num_lba,
+                partition_lba_ranges,
+                disk_size_in_lba,
+            }
+        }
+
+        pub fn primary_header_lba(&self) -> u64 {
+            1
+        }
+
+        pub fn lba_range_to_byte_range(&self, lba_range: &Range<u64>) -> Range<u64> {
+            (lba_range.start * self.logical_block_size)..(lba_range.end * self.logical_block_size)
+        }
+
+        pub fn primary_partition_entry_lba(&self) -> u64 {
+            self.primary_header_lba() + 1
+        }
+
+        pub fn backup_header_lba(&self) -> u64 {
+            self.disk_size_in_lba - 1
+        }
+
+        pub fn backup_partition_entry_lba(&self) -> u64 {
+            // the backup partition entry array immediately precedes the backup gpt header
+            self.backup_header_lba() - self.partition_array_num_lba
+        }
+
+        pub fn first_usable_lba(&self) -> u64 {
+            self.primary_partition_entry_lba() + self.partition_array_num_lba
+        }
+
+        pub fn last_usable_lba(&self) -> u64 {
+            self.backup_partition_entry_lba() - 1
+        }
+    }
+}
+
+/// A partition to be created by a `GptBuilder`
+#[derive(Debug, Clone)]
+pub struct NewPartition {
+    pub size_in_bytes: u64,
+    pub partition_type_guid: Uuid,
+    pub unique_partition_guid: Uuid,
+    pub partition_name: String,
+    pub attributes: u64,
+}
+
+impl NewPartition {
+    /// A partition with a random unique GUID and no attributes set
+    pub fn new(size_in_bytes: u64, partition_type_guid: Uuid, partition_name: &str) -> Self {
+        Self {
+            size_in_bytes,
+            partition_type_guid,
+            unique_partition_guid: Uuid::new_v4(),
+            partition_name: partition_name.to_string(),
+            attributes: 0,
+        }
+    }
+
+    pub fn efi_system_partition(size_in_bytes: u64, partition_name: &str) -> Self {
+        let mut partition = Self::new(
+            size_in_bytes,
+            Uuid::parse_str(partition_type_guid::EFI_SYSTEM_PARTITION).unwrap(),
+            partition_name,
+        );
+        partition.attributes = 1 << partition_attributes::REQUIRED_PARTITION;
+        partition
+    }
+}
+
+/// The backup partition entry array and backup GPT header, which live at the end of the disk
+/// after the partitions. Returned by `write_header` so the backup matches the primary header.
+pub struct Footer {
+    partition_padding_size: u64,
+    partition_entry_array_blocks: Vec<u8>,
+    backup_header: Vec<u8>,
+}
+
+struct EncodedGpt {
+    layout: create::Layout,
+    mbr: Vec<u8>,
+    header: Vec<u8>,
+    partition_entry_array_blocks: Vec<u8>,
+    backup_header: Vec<u8>,
+}
+
+/// Describes a disk containing a GPT and an ordered list of partitions. Partitions are laid out
+/// consecutively in the order they were added.
+#[derive(Debug, Clone)]
+pub struct GptBuilder {
+    disk_guid: Uuid,
+    logical_block_size: u64,
+    partitions: Vec<NewPartition>,
 }
 
+impl Default for GptBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GptBuilder {
+    /// A builder with a random disk GUID and no partitions
+    pub fn new() -> Self {
+        Self {
+            disk_guid: Uuid::new_v4(),
+            logical_block_size: DEFAULT_LOGICAL_BLOCK_SIZE,
+            partitions: Vec::new(),
+        }
+    }
+
+    /// Must be one of `SUPPORTED_LOGICAL_BLOCK_SIZES`
+    pub fn logical_block_size(mut self, logical_block_size: u64) -> Self {
+        self.logical_block_size = logical_block_size;
+        self
+    }
+
+    pub fn disk_guid(mut self, disk_guid: Uuid) -> Self {
+        self.disk_guid = disk_guid;
+        self
+    }
+
+    pub fn partition(mut self, partition: NewPartition) -> Self {
+        self.partitions.push(partition);
+        self
+    }
+
+    fn layout(&self) -> create::Layout {
+        create::Layout::new(
+            self.logical_block_size,
+            self.partitions.iter().map(|p| {
+                size_in_bytes_to_num_logical_blocks(p.size_in_bytes, self.logical_block_size)
+            }),
+        )
+    }
+
+    pub fn disk_size_in_bytes(&self) -> u64 {
+        self.layout().disk_size_in_lba * self.logical_block_size
+    }
+
+    /// The byte range within the disk of each partition, in the order the partitions were added
+    pub fn partition_byte_ranges(&self) -> Vec<Range<u64>> {
+        let layout = self.layout();
+        layout
+            .partition_lba_ranges
+            .iter()
+            .map(|r| layout.lba_range_to_byte_range(r))
+            .collect()
+    }
+
+    fn encode(&self) -> Result<EncodedGpt, Error> {
+        if !SUPPORTED_LOGICAL_BLOCK_SIZES.contains(&self.logical_block_size) {
+            return Err(GptError::UnsupportedLogicalBlockSize(self.logical_block_size).into());
+        }
+        if self.partitions.is_empty() {
+            return Err(GptError::NoPartitions.into());
+        }
+        if let Some(partition) = self.partitions.iter().find(|p| p.size_in_bytes == 0) {
+            return Err(GptError::EmptyPartition(partition.partition_name.clone()).into());
+        }
+        let layout = self.layout();
+        let mbr_raw = Mbr::new_protective_with_disk_size_in_lba(
+            layout.disk_size_in_lba,
+            layout.logical_block_size,
+        )
+        .encode();
+        if let Err(ref e) = Mbr::parse(&mbr_raw) {
+            eprintln!("Failed to parse generated MBR");
+            die(e);
+        }
+        // the mbr occupies the start of the first logical block
+        let mut mbr_block = vec![0; layout.logical_block_size as usize];
+        mbr_block[0..mbr::SIZE].copy_from_slice(&mbr_raw);
+        let partition_entry_array = PartitionEntry::new_array_raw(&self.partitions, &layout);
+        let header = GptHeader::new_raw(
+            &layout,
+            self.disk_guid,
+            &partition_entry_array,
+            HeaderLocation::Primary,
+        );
+        let header_parsed = match GptHeader::parse(&header) {
+            Ok(header) => header,
+            Err(ref e) => {
+                eprintln!("Failed to parse generated GPT header");
+                die(e);
+            }
+        };
+        if let Err(ref e) = PartitionEntry::parse_array(&partition_entry_array, &header_parsed) {
+            eprintln!("Failed to parse generated partition entry array");
+            die(e);
+        }
+        let backup_header = GptHeader::new_raw(
+            &layout,
+            self.disk_guid,
+            &partition_entry_array,
+            HeaderLocation::Backup,
+        );
+        match GptHeader::parse(&backup_header) {
+            Ok(backup_header_parsed) => {
+                if let Err(ref e) = GptHeader::compare_header_and_backup_header(
+                    &header_parsed,
+                    &backup_header_parsed,
+                ) {
+                    eprintln!("Generated backup GPT header does not match generated GPT header");
+                    die(e);
+                }
+            }
+            Err(ref e) => {
+                eprintln!("Failed to parse generated backup GPT header");
+                die(e);
+            }
+        }
+        // The checksum only covers the partition entries, but the array occupies whole logical
+        // blocks on disk.
+        let mut partition_entry_array_blocks = partition_entry_array;
+        partition_entry_array_blocks.resize(
+            (layout.partition_array_num_lba * layout.logical_block_size) as usize,
+            0,
+        );
+        Ok(EncodedGpt {
+            layout,
+            mbr: mbr_block,
+            header,
+            partition_entry_array_blocks,
+            backup_header,
+        })
+    }
+
+    /// Writes the entire disk. The contents of each partition are written by calling
+    /// `write_partition` with the partition's index and a writer which accepts at most the
+    /// partition's size in bytes. Partitions are padded with zeroes to fill their space on disk.
+    pub fn write<H, F>(&self, handle: &mut H, mut write_partition: F) -> Result<(), Error>
+    where
+        H: io::Write,
+        F: FnMut(usize, &mut PartitionWriter<H>) -> Result<(), Error>,
+    {
+        let encoded = self.encode()?;
+        handle.write_all(&encoded.mbr)?;
+        handle.write_all(&encoded.header)?;
+        handle.write_all(&encoded.partition_entry_array_blocks)?;
+        for (index, lba_range) in encoded.layout.partition_lba_ranges.iter().enumerate() {
+            let byte_range = encoded.layout.lba_range_to_byte_range(lba_range);
+            let size = byte_range.end - byte_range.start;
+            let mut partition_writer = PartitionWriter {
+                handle,
+                remaining: size,
+            };
+            write_partition(index, &mut partition_writer)?;
+            let padding_size = partition_writer.remaining;
+            io::copy(&mut io::Read::take(io::repeat(0), padding_size), handle)?;
+        }
+        handle.write_all(&encoded.partition_entry_array_blocks)?;
+        handle.write_all(&encoded.backup_header)?;
+        Ok(())
+    }
+}
+
+/// Passed to the callback of `GptBuilder::write` to write the contents of a partition
+pub struct PartitionWriter<'a, H> {
+    handle: &'a mut H,
+    remaining: u64,
+}
+
+impl<'a, H> io::Write for PartitionWriter<'a, H>
+where
+    H: io::Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::other(GptError::PartitionContentsTooLarge));
+        }
+        let num_bytes_written = self.handle.write(buf)?;
+        self.remaining -= num_bytes_written as u64;
+        Ok(num_bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.flush()
+    }
+}
+
+/// Writes the protective MBR, GPT header and partition entry array for a disk with a single EFI
+/// system partition. The disk and partition GUIDs are random - use `GptBuilder` to choose them.
 pub fn write_header<H>(
     handle: &mut H,
     partition_size_bytes: u64,
     partition_name: &str,
-) -> Result<(), Error>
+) -> Result<Footer, Error>
 where
     H: io::Write,
 {
-    let partition_size_in_lba = size_in_bytes_to_num_logical_blocks(partition_size_bytes);
-    let disk_size_in_lba = disk_size_in_lba(partition_size_in_lba);
-    let mbr_raw = Mbr::new_protective_with_disk_size_in_lba(disk_size_in_lba).encode();
-    if let Err(ref e) = Mbr::parse(&mbr_raw) {
-        eprintln!("Failed to parse generated MBR");
-        die(e);
-    }
-    handle.write_all(&mbr_raw)?;
-    let partition_entry_array = PartitionEntry::new_array_single_partition_raw(
-        partition_size_in_lba,
-        Uuid::new_v4(),
-        partition_name.to_string(),
-    );
-    let gpt_header = GptHeader::new_single_partition_raw(
-        disk_size_in_lba,
-        Uuid::new_v4(),
-        &partition_entry_array,
-    );
-    let header_parsed = match GptHeader::parse(&gpt_header) {
-        Ok(header) => header,
-        Err(ref e) => {
-            eprintln!("Failed to parse generated GPT header");
-            die(e);
-        }
-    };
-    if let Err(ref e) = PartitionEntry::parse_array(&partition_entry_array, &header_parsed) {
-        eprintln!("Failed to parse generated partition entry array");
-        die(e);
-    }
-    handle.write_all(&gpt_header)?;
-    handle.write_all(&partition_entry_array)?;
+    let encoded = GptBuilder::new()
+        .partition(NewPartition::efi_system_partition(
+            partition_size_bytes,
+            partition_name,
+        ))
+        .encode()?;
+    handle.write_all(&encoded.mbr)?;
+    handle.write_all(&encoded.header)?;
+    handle.write_all(&encoded.partition_entry_array_blocks)?;
+    let partition_byte_range = encoded
+        .layout
+        .lba_range_to_byte_range(&encoded.layout.partition_lba_ranges[0]);
+    Ok(Footer {
+        partition_padding_size: (partition_byte_range.end - partition_byte_range.start)
+            - partition_size_bytes,
+        partition_entry_array_blocks: encoded.partition_entry_array_blocks,
+        backup_header: encoded.backup_header,
+    })
+}
+
+/// Writes the backup partition entry array and backup GPT header. The handle must be positioned
+/// immediately after the partition contents (ie. `partition_size_bytes` after the end of the data
+/// written by `write_header`).
+pub fn write_footer<H>(handle: &mut H, footer: &Footer) -> Result<(), Error>
+where
+    H: io::Write,
+{
+    // pad the partition to a whole number of logical blocks
+    handle.write_all(&vec![0; footer.partition_padding_size as usize])?;
+    handle.write_all(&footer.partition_entry_array_blocks)?;
+    handle.write_all(&footer.backup_header)?;
     Ok(())
 }
 
@@ -661,3 +1144,146 @@ fn die(error: &Error) -> ! {
     eprintln!("{}", error.backtrace());
     std::process::exit(1);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_then_read() {
+        let partition_size_bytes = 1000;
+        let mut disk = io::Cursor::new(Vec::new());
+        let footer = write_header(&mut disk, partition_size_bytes, "test").unwrap();
+        io::Write::write_all(&mut disk, &vec![0; partition_size_bytes as usize]).unwrap();
+        write_footer(&mut disk, &footer).unwrap();
+        let info = gpt_info(&mut disk).unwrap();
+        let backup_header = info.backup_header.as_ref().unwrap();
+        assert!(backup_header.comparison.is_ok());
+        assert!(backup_header.partition_entry_array_comparison.is_ok());
+        assert_eq!(
+            disk.get_ref().len() as u64,
+            (info.header.alternate_lba + 1) * DEFAULT_LOGICAL_BLOCK_SIZE
+        );
+        // 16KiB is reserved for the partition entry array
+        assert_eq!(info.header.number_of_partition_entries, 128);
+        assert_eq!(
+            info.first_partition_byte_range().unwrap(),
+            (34 * DEFAULT_LOGICAL_BLOCK_SIZE)..(36 * DEFAULT_LOGICAL_BLOCK_SIZE)
+        );
+    }
+
+    #[test]
+    fn damaged_backup() {
+        let partition_size_bytes = 1000;
+        let mut disk = io::Cursor::new(Vec::new());
+        let footer = write_header(&mut disk, partition_size_bytes, "test").unwrap();
+        io::Write::write_all(&mut disk, &vec![0; partition_size_bytes as usize]).unwrap();
+        write_footer(&mut disk, &footer).unwrap();
+        let backup_header_offset = disk.get_ref().len() - DEFAULT_LOGICAL_BLOCK_SIZE as usize;
+        // the end of the backup partition entry array immediately precedes the backup header
+        let mut damaged_array = disk.clone();
+        damaged_array.get_mut()[backup_header_offset - 1] ^= 0xFF;
+        let info = gpt_info(&mut damaged_array).unwrap();
+        let backup_header = info.backup_header.as_ref().unwrap();
+        assert!(backup_header.comparison.is_ok());
+        assert!(backup_header.partition_entry_array_comparison.is_err());
+        assert!(info.backup_header_error().is_some());
+        assert_eq!(info.partitions().count(), 1);
+        // a backup header with a valid checksum but a different last usable lba
+        let mut damaged_header = disk;
+        let header_bytes = &mut damaged_header.get_mut()[backup_header_offset..];
+        header_bytes[48] ^= 1;
+        header_bytes[16..20].copy_from_slice(&[0; 4]);
+        let header_crc32 = crc32::crc32(&header_bytes[0..MIN_HEADER_SIZE as usize]);
+        header_bytes[16..20].copy_from_slice(&header_crc32.to_le_bytes());
+        let info = gpt_info(&mut damaged_header).unwrap();
+        let backup_header = info.backup_header.as_ref().unwrap();
+        assert!(backup_header.comparison.is_err());
+        assert!(backup_header.partition_entry_array_comparison.is_ok());
+        assert!(info.backup_header_error().is_some());
+    }
+
+    #[test]
+    fn short_disk_without_signature() {
+        // long enough to probe for a 512 byte logical block size, but not for 4096
+        let mut disk = io::Cursor::new(vec![0; 1024]);
+        let mbr = Mbr::new_protective_with_disk_size_in_lba(2, DEFAULT_LOGICAL_BLOCK_SIZE);
+        disk.get_mut()[0..mbr::SIZE].copy_from_slice(&mbr.encode());
+        let error = gpt_info(&mut disk).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<GptError>(),
+            Some(GptError::InvalidSignature(0))
+        ));
+    }
+
+    #[test]
+    fn multiple_partitions() {
+        let linux_root = Uuid::parse_str(partition_type_guid::LINUX_ROOT_X86_64).unwrap();
+        let builder = GptBuilder::new()
+            .partition(NewPartition::efi_system_partition(2000, "efi"))
+            .partition(NewPartition::new(4096, linux_root, "root"))
+            .partition(NewPartition::new(512, linux_root, "scratch"));
+        let mut disk = io::Cursor::new(Vec::new());
+        builder
+            .write(&mut disk, |index, partition| {
+                io::Write::write_all(partition, &[index as u8 + 1; 512])?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(disk.get_ref().len() as u64, builder.disk_size_in_bytes());
+        let info = gpt_info(&mut disk).unwrap();
+        assert!(info.backup_header.as_ref().unwrap().comparison.is_ok());
+        let byte_ranges = builder.partition_byte_ranges();
+        assert_eq!(byte_ranges[0].start, info.header.first_usable_lba * 512);
+        assert_eq!(byte_ranges[2].end, (info.header.last_usable_lba + 1) * 512);
+        assert_eq!(info.partitions().count(), 3);
+        for (index, byte_range) in byte_ranges.iter().enumerate() {
+            let partition = info.partition(index).unwrap();
+            assert_eq!(partition.byte_range(), *byte_range);
+            assert_eq!(disk.get_ref()[byte_range.start as usize], index as u8 + 1);
+        }
+        assert!(info.partition(3).is_none());
+        let root = info.partition_by_name("root").unwrap();
+        assert_eq!(root.index(), 1);
+        assert_eq!(root.partition_type_guid(), linux_root);
+        let scratch = info.partition(2).unwrap();
+        assert_eq!(
+            info.partition_by_unique_guid(scratch.unique_partition_guid())
+                .unwrap()
+                .name(),
+            "scratch"
+        );
+        assert!(builder
+            .write(&mut io::sink(), |_, partition| {
+                io::Write::write_all(partition, &[0; 1024])?;
+                Ok(())
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn logical_block_size_4096() {
+        let builder = GptBuilder::new()
+            .logical_block_size(4096)
+            .partition(NewPartition::efi_system_partition(5000, "efi"));
+        let mut disk = io::Cursor::new(Vec::new());
+        builder.write(&mut disk, |_, _| Ok(())).unwrap();
+        assert_eq!(disk.get_ref().len() as u64, builder.disk_size_in_bytes());
+        let info = gpt_info(&mut disk).unwrap();
+        assert_eq!(info.logical_block_size(), 4096);
+        assert!(info.backup_header.as_ref().unwrap().comparison.is_ok());
+        assert_eq!(
+            info.first_partition_byte_range().unwrap(),
+            builder.partition_byte_ranges()[0]
+        );
+        assert_eq!(
+            info.first_partition_byte_range().unwrap(),
+            (6 * 4096)..(8 * 4096)
+        );
+        assert!(GptBuilder::new()
+            .logical_block_size(1000)
+            .partition(NewPartition::efi_system_partition(5000, "efi"))
+            .write(&mut io::sink(), |_, _| Ok(()))
+            .is_err());
+    }
+}
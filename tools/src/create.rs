@@ -1,50 +1,195 @@
+use anyhow::{anyhow, Context, Error};
 use std::fs::File;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process;
 
 mod error;
+mod glob;
+mod import;
+mod manifest;
 
 struct Args {
-    path_pairs: Vec<mini_fat::PathPair>,
+    local_and_disk_image_paths: Vec<(PathBuf, PathBuf)>,
+    import_options: import::Options,
+    directories: Vec<String>,
+    manifest: Option<PathBuf>,
     output: Box<dyn io::Write>,
-    partition_name: String,
+    partition_name: Option<String>,
+    partition_guid: Option<mini_gpt::Uuid>,
+    disk_guid: Option<mini_gpt::Uuid>,
+    seed: Option<String>,
+    volume_label: Option<String>,
+    volume_id: Option<u32>,
+    oem_name: Option<String>,
+    hidden: Vec<PathBuf>,
+    system: Vec<PathBuf>,
+    timestamp: Option<std::time::SystemTime>,
+    size: Option<u64>,
+    free_space: Option<mini_fat::PartitionSize>,
+    fat_type: Option<mini_fat::FatType>,
+    bytes_per_sector: Option<u16>,
+    sectors_per_cluster: Option<u8>,
+    logical_block_size: u64,
 }
 
 impl Args {
     fn parse() -> Self {
         (meap::let_map! {
             let {
-                local_filesystem_paths = opt_multi::<String, _>("PATH", 'l')
+                local_filesystem_paths = opt_multi::<PathBuf, _>("PATH", 'l')
                     .name("local")
-                    .desc("paths to local files to include in image (corresponds to -d)");
-                disk_image_paths = opt_multi("PATH", 'd')
-                    .name("disk") .desc("paths in disk image where files will be stored (corresponds to -l)");
+                    .desc("paths to local files or directories to include in image (corresponds to -d)");
+                disk_image_paths = opt_multi::<PathBuf, _>("PATH", 'd')
+                    .name("disk")
+                    .desc("paths in disk image where files or directory contents will be stored (corresponds to -l)");
+                skip_symlinks = flag('s')
+                    .name("skip-symlinks")
+                    .desc("skip symlinks found in directories instead of following them");
+                exclude = opt_multi::<glob::Pattern, _>("PATTERN", 'x')
+                    .name("exclude")
+                    .desc("skip files and directories in directories matching a glob pattern");
+                directories = opt_multi::<String, _>("PATH", 'D')
+                    .name("directory")
+                    .desc("paths in disk image of directories to create, which may be empty");
+                manifest = opt_opt::<PathBuf, _>("PATH", 'm')
+                    .name("manifest")
+                    .desc("path to manifest describing the partitions and their contents (instead of -l, -d, -D, -n, -g, -L, -i, -O, -H, -y, -t, -p, -F, -f, -S and -c)");
                 output = opt_opt::<String, _>("PATH", 'o').name("output").desc("output file path (omit for stdout)");
                 partition_name = opt_opt::<String, _>("PARTITION_NAME", 'n')
                     .name("partition-name")
-                    .desc("name of partition")
-                    .with_default_parse("efi");
+                    .desc("name of partition (defaults to \"efi\")");
+                partition_guid = opt_opt::<String, _>("GUID", 'g')
+                    .name("partition-guid")
+                    .desc("unique GUID of partition (omit for a random GUID, or one derived from the seed)");
+                disk_guid = opt_opt::<String, _>("GUID", 'G')
+                    .name("disk-guid")
+                    .desc("GUID of disk (omit for a random GUID, or one derived from the seed)");
+                seed = opt_opt::<String, _>("SEED", 'r')
+                    .name("seed")
+                    .desc("derive GUIDs and volume IDs from a seed instead of picking them randomly, to create reproducible images along with $SOURCE_DATE_EPOCH (use a different seed for each image)");
+                volume_label = opt_opt::<String, _>("LABEL", 'L')
+                    .name("label")
+                    .desc("FAT volume label (up to 11 upper case letters, digits, spaces or characters allowed in short names)");
+                volume_id = opt_opt::<String, _>("ID", 'i')
+                    .name("volume-id")
+                    .desc("FAT volume ID in hex (eg. 1234-ABCD - omit to derive from the current time)");
+                oem_name = opt_opt::<String, _>("NAME", 'O')
+                    .name("oem-name")
+                    .desc("OEM name stored in FAT boot sector (up to 8 characters)");
+                hidden = opt_multi::<PathBuf, _>("PATH", 'H')
+                    .name("hidden")
+                    .desc("paths in disk image of files or directories to mark as hidden");
+                system = opt_multi::<PathBuf, _>("PATH", 'y')
+                    .name("system")
+                    .desc("paths in disk image of files or directories to mark as system files");
+                timestamp = opt_opt::<String, _>("SECONDS", 't')
+                    .name("timestamp")
+                    .desc("fixed timestamp for all files and directories in seconds since the unix epoch (omit to use the timestamps of local files)");
+                size = opt_opt::<String, _>("SIZE", 'p')
+                    .name("partition-size")
+                    .desc("size of partition (eg. 64MiB - omit to fit the contents)");
+                free_space = opt_opt::<String, _>("SIZE", 'F')
+                    .name("free")
+                    .desc("free space to leave in partition in addition to its contents (eg. 16MiB or 25%)");
+                fat_type = opt_opt::<String, _>("BITS", 'f')
+                    .name("fat-type")
+                    .desc("FAT type of partition (12, 16 or 32 - omit to pick the smallest which fits the contents)");
+                bytes_per_sector = opt_opt::<u16, _>("BYTES", 'S')
+                    .name("sector-size")
+                    .desc("FAT logical sector size (512, 1024, 2048 or 4096 - defaults to the logical block size)");
+                sectors_per_cluster = opt_opt::<u8, _>("COUNT", 'c')
+                    .name("sectors-per-cluster")
+                    .desc("number of sectors per FAT cluster (a power of 2 - omit to pick based on partition size)");
+                logical_block_size = opt_opt::<u64, _>("BYTES", 'b')
+                    .name("logical-block-size")
+                    .desc("logical block size of the disk (512 or 4096)")
+                    .with_default(mini_gpt::DEFAULT_LOGICAL_BLOCK_SIZE);
             } in {{
                 if local_filesystem_paths.len() != disk_image_paths.len() {
                     eprintln!("Error: -l and -d must be passed the same number of times.");
                     process::exit(1);
                 }
-                let path_pairs = local_filesystem_paths
-                    .into_iter()
-                    .zip(disk_image_paths.into_iter())
-                    .map(|(in_local_filesystem, in_disk_image)| mini_fat::PathPair {
-                        in_local_filesystem: File::open(in_local_filesystem).unwrap(),
-                        in_disk_image,
+                // settings of the single partition created without a manifest
+                let partition_options = [
+                    ('l', !local_filesystem_paths.is_empty()),
+                    ('d', !disk_image_paths.is_empty()),
+                    ('D', !directories.is_empty()),
+                    ('n', partition_name.is_some()),
+                    ('g', partition_guid.is_some()),
+                    ('L', volume_label.is_some()),
+                    ('i', volume_id.is_some()),
+                    ('O', oem_name.is_some()),
+                    ('H', !hidden.is_empty()),
+                    ('y', !system.is_empty()),
+                    ('t', timestamp.is_some()),
+                    ('p', size.is_some()),
+                    ('F', free_space.is_some()),
+                    ('f', fat_type.is_some()),
+                    ('S', bytes_per_sector.is_some()),
+                    ('c', sectors_per_cluster.is_some()),
+                ];
+                if manifest.is_some() {
+                    if let Some((option, _)) = partition_options.iter().find(|(_, given)| *given) {
+                        eprintln!("Error: -m may not be combined with -{}.", option);
+                        process::exit(1);
+                    }
+                }
+                if size.is_some() && free_space.is_some() {
+                    eprintln!("Error: -p may not be combined with -F.");
+                    process::exit(1);
+                }
+                fn parse_or_exit<T>(result: Result<T, String>) -> T {
+                    result.unwrap_or_else(|e| {
+                        eprintln!("Error: {}.", e);
+                        process::exit(1);
                     })
-                    .collect();
+                }
+                let partition_guid = partition_guid
+                    .map(|partition_guid| parse_or_exit(manifest::parse_guid(&partition_guid)));
+                let disk_guid =
+                    disk_guid.map(|disk_guid| parse_or_exit(manifest::parse_guid(&disk_guid)));
+                let volume_id =
+                    volume_id.map(|volume_id| parse_or_exit(manifest::parse_volume_id(&volume_id)));
+                let timestamp =
+                    timestamp.map(|timestamp| parse_or_exit(manifest::parse_timestamp(&timestamp)));
+                let size = size.map(|size| parse_or_exit(manifest::parse_size(&size)));
+                let free_space = free_space
+                    .map(|free_space| parse_or_exit(manifest::parse_free_space(&free_space)));
+                let fat_type =
+                    fat_type.map(|fat_type| parse_or_exit(manifest::parse_fat_type(&fat_type)));
                 Self {
-                    path_pairs,
+                    local_and_disk_image_paths: local_filesystem_paths
+                        .into_iter()
+                        .zip(disk_image_paths)
+                        .collect(),
+                    import_options: import::Options {
+                        follow_symlinks: !skip_symlinks,
+                        exclude,
+                    },
+                    directories,
+                    manifest,
                     output: if let Some(path) = output {
                         Box::new(File::create(path).unwrap())
                     } else {
                         Box::new(io::stdout())
                     },
                     partition_name,
+                    partition_guid,
+                    disk_guid,
+                    seed,
+                    volume_label,
+                    volume_id,
+                    oem_name,
+                    hidden,
+                    system,
+                    timestamp,
+                    size,
+                    free_space,
+                    fat_type,
+                    bytes_per_sector,
+                    sectors_per_cluster,
+                    logical_block_size,
                 }
             }}
         })
@@ -53,18 +198,282 @@ impl Args {
     }
 }
 
+/// Describes the location of the setting which caused an error, for error messages
+struct Source<'a> {
+    manifest: Option<&'a Path>,
+}
+
+impl<'a> Source<'a> {
+    fn at_line(&self, line: usize) -> String {
+        match self.manifest {
+            Some(path) => format!("{}:{}", path.display(), line),
+            None => "command line".to_string(),
+        }
+    }
+}
+
+/// Derives GUIDs and volume IDs for creating reproducible images
+struct Seed(String);
+
+impl Seed {
+    fn guid(&self, purpose: &str) -> mini_gpt::Uuid {
+        mini_gpt::guid_from_seed(format!("{}/{}", self.0, purpose).as_bytes())
+    }
+
+    fn disk_guid(&self) -> mini_gpt::Uuid {
+        self.guid("disk")
+    }
+
+    fn partition_guid(&self, index: usize) -> mini_gpt::Uuid {
+        self.guid(&format!("partition/{}", index))
+    }
+
+    fn volume_id(&self, index: usize) -> u32 {
+        self.guid(&format!("volume-id/{}", index)).as_u128() as u32
+    }
+}
+
+fn import_partition_contents(
+    partition: &manifest::Partition,
+    import_options: &import::Options,
+    source: &Source,
+) -> Result<import::Import, Error> {
+    let import_options = import::Options {
+        follow_symlinks: import_options.follow_symlinks,
+        exclude: import_options
+            .exclude
+            .iter()
+            .chain(partition.exclude.iter())
+            .cloned()
+            .collect(),
+    };
+    let mut import = import::Import::default();
+    for file in &partition.files {
+        import
+            .add(
+                &file.in_local_filesystem,
+                &file.in_disk_image,
+                &import_options,
+            )
+            .with_context(|| source.at_line(file.line))?;
+    }
+    Ok(import)
+}
+
+/// The FAT sector size defaults to the disk's logical block size
+fn fat_builder<'a>(
+    partition: &'a manifest::Partition,
+    index: usize,
+    import: &'a import::Import,
+    logical_block_size: u64,
+    seed: Option<&Seed>,
+) -> mini_fat::FatBuilder<'a> {
+    let fat = import
+        .directories
+        .iter()
+        .chain(partition.directories.iter())
+        .fold(
+            mini_fat::FatBuilder::new().path_pairs(&import.path_pairs),
+            |fat, directory| fat.directory(directory),
+        );
+    let fat = match partition.volume_label {
+        Some(ref volume_label) => fat.volume_label(volume_label),
+        None => fat,
+    };
+    let volume_id = partition
+        .volume_id
+        .or_else(|| seed.map(|seed| seed.volume_id(index)));
+    let fat = match volume_id {
+        Some(volume_id) => fat.volume_id(volume_id),
+        None => fat,
+    };
+    let fat = match partition.oem_name {
+        Some(ref oem_name) => fat.oem_name(oem_name),
+        None => fat,
+    };
+    let fat = partition
+        .hidden
+        .iter()
+        .fold(fat, |fat, path| fat.hidden(path));
+    let fat = partition
+        .system
+        .iter()
+        .fold(fat, |fat, path| fat.system(path));
+    let fat = match partition.timestamp {
+        Some(timestamp) => fat.timestamp(timestamp),
+        None => fat,
+    };
+    let fat = match partition.fat_type {
+        Some(fat_type) => fat.fat_type(fat_type),
+        None => fat,
+    };
+    let fat = fat.bytes_per_sector(
+        partition
+            .bytes_per_sector
+            .unwrap_or(logical_block_size as u16),
+    );
+    let fat = match partition.sectors_per_cluster {
+        Some(sectors_per_cluster) => fat.sectors_per_cluster(sectors_per_cluster),
+        None => fat,
+    };
+    match (partition.size_in_bytes, partition.free_space) {
+        (Some(size_in_bytes), _) => fat.size(mini_fat::PartitionSize::Bytes(size_in_bytes)),
+        (None, Some(free_space)) => fat.size(free_space),
+        (None, None) => fat,
+    }
+}
+
+fn new_partition(
+    partition: &manifest::Partition,
+    index: usize,
+    fat: Option<&mini_fat::FatBuilder>,
+    seed: Option<&Seed>,
+    source: &Source,
+) -> Result<mini_gpt::NewPartition, Error> {
+    // FAT partitions check that their contents fit in the requested size
+    let fat_size = match fat {
+        Some(fat) => Some(
+            fat.partition_size()
+                .with_context(|| source.at_line(partition.line))?,
+        ),
+        None => None,
+    };
+    let size_in_bytes = partition
+        .size_in_bytes
+        .or(fat_size)
+        .ok_or_else(|| anyhow!("{}: partition size unknown", source.at_line(partition.line)))?;
+    let efi_system_partition =
+        mini_gpt::Uuid::parse_str(mini_gpt::partition_type_guid::EFI_SYSTEM_PARTITION).unwrap();
+    let mut new_partition = if partition.partition_type_guid == efi_system_partition {
+        mini_gpt::NewPartition::efi_system_partition(size_in_bytes, &partition.name)
+    } else {
+        mini_gpt::NewPartition::new(
+            size_in_bytes,
+            partition.partition_type_guid,
+            &partition.name,
+        )
+    };
+    if let Some(unique_partition_guid) = partition
+        .unique_partition_guid
+        .or_else(|| seed.map(|seed| seed.partition_guid(index)))
+    {
+        new_partition.unique_partition_guid = unique_partition_guid;
+    }
+    Ok(new_partition)
+}
+
 fn main() {
     let Args {
-        path_pairs,
+        local_and_disk_image_paths,
+        import_options,
+        directories,
+        manifest,
         mut output,
         partition_name,
+        partition_guid,
+        disk_guid,
+        seed,
+        volume_label,
+        volume_id,
+        oem_name,
+        hidden,
+        system,
+        timestamp,
+        size,
+        free_space,
+        fat_type,
+        bytes_per_sector,
+        sectors_per_cluster,
+        logical_block_size,
     } = Args::parse();
     env_logger::init();
-    let partition_size = error::or_die(mini_fat::partition_size(&path_pairs));
-    if let Err(ref e) = mini_gpt::write_header(&mut output, partition_size, &partition_name) {
-        error::die(e);
-    }
-    if let Err(ref e) = mini_fat::write_partition(&mut output, &path_pairs) {
-        error::die(e);
-    }
+    let partitions = match manifest {
+        Some(ref path) => error::or_die(manifest::Manifest::read(path)).partitions,
+        None => vec![manifest::Partition {
+            line: 0,
+            name: partition_name.unwrap_or_else(|| "efi".to_string()),
+            partition_type_guid: mini_gpt::Uuid::parse_str(
+                mini_gpt::partition_type_guid::EFI_SYSTEM_PARTITION,
+            )
+            .unwrap(),
+            unique_partition_guid: partition_guid,
+            size_in_bytes: size,
+            free_space,
+            fat: true,
+            volume_label,
+            volume_id,
+            oem_name,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            files: local_and_disk_image_paths
+                .into_iter()
+                .map(
+                    |(in_local_filesystem, in_disk_image)| manifest::FileMapping {
+                        in_local_filesystem,
+                        in_disk_image,
+                        line: 0,
+                    },
+                )
+                .collect(),
+            directories: directories.into_iter().map(PathBuf::from).collect(),
+            exclude: Vec::new(),
+            hidden,
+            system,
+            timestamp,
+        }],
+    };
+    let source = Source {
+        manifest: manifest.as_deref(),
+    };
+    // SOURCE_DATE_EPOCH is only used for timestamps, since images built together would otherwise
+    // share their GUIDs and volume IDs
+    let seed = seed.map(Seed);
+    let imports = partitions
+        .iter()
+        .map(|partition| {
+            if partition.fat {
+                Some(error::or_die(import_partition_contents(
+                    partition,
+                    &import_options,
+                    &source,
+                )))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let fats = partitions
+        .iter()
+        .zip(imports.iter())
+        .enumerate()
+        .map(|(index, (partition, import))| {
+            import.as_ref().map(|import| {
+                fat_builder(partition, index, import, logical_block_size, seed.as_ref())
+            })
+        })
+        .collect::<Vec<_>>();
+    let gpt = mini_gpt::GptBuilder::new().logical_block_size(logical_block_size);
+    let gpt = match disk_guid.or_else(|| seed.as_ref().map(Seed::disk_guid)) {
+        Some(disk_guid) => gpt.disk_guid(disk_guid),
+        None => gpt,
+    };
+    let gpt = partitions.iter().zip(fats.iter()).enumerate().fold(
+        gpt,
+        |gpt, (index, (partition, fat))| {
+            gpt.partition(error::or_die(new_partition(
+                partition,
+                index,
+                fat.as_ref(),
+                seed.as_ref(),
+                &source,
+            )))
+        },
+    );
+    error::or_die(
+        gpt.write(&mut output, |index, partition| match fats[index] {
+            Some(ref fat) => fat.write(partition),
+            None => Ok(()),
+        }),
+    );
 }
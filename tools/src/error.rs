@@ -2,7 +2,8 @@ use anyhow::Error;
 
 #[allow(dead_code)]
 pub fn die(error: &Error) -> ! {
-    eprintln!("{}", error);
+    // the alternate format includes the causes of errors with added context
+    eprintln!("{:#}", error);
     #[cfg(feature = "backtrace")]
     eprintln!("{}", error.backtrace());
     std::process::exit(1);
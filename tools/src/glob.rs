@@ -0,0 +1,107 @@
+// Minimal glob patterns for excluding paths. `*` matches any characters other than `/`, `?`
+// matches a single character other than `/`, and `**` matches any characters including `/`.
+// Patterns containing a `/` are matched against a whole relative path. Other patterns are
+// matched against the final component of the path.
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Char(char),
+    AnyChar,
+    AnyWithinComponent,
+    Any,
+    // "**/", which also matches nothing at all so that "**/x" matches "x"
+    AnyDirectories,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+    match_file_name_only: bool,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let match_file_name_only = !s.contains('/');
+        let mut tokens = Vec::new();
+        let mut chars = s.trim_start_matches('/').chars().peekable();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '?' => Token::AnyChar,
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        Token::AnyDirectories
+                    } else {
+                        Token::Any
+                    }
+                }
+                '*' => Token::AnyWithinComponent,
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+        }
+        Ok(Self {
+            tokens,
+            match_file_name_only,
+        })
+    }
+}
+
+fn matches(tokens: &[Token], s: &[char]) -> bool {
+    match tokens.split_first() {
+        None => s.is_empty(),
+        Some((&Token::Char(c), rest)) => s.first() == Some(&c) && matches(rest, &s[1..]),
+        Some((Token::AnyChar, rest)) => {
+            s.first().is_some_and(|&c| c != '/') && matches(rest, &s[1..])
+        }
+        Some((Token::AnyWithinComponent, rest)) => (0..=s.len())
+            .take_while(|&i| i == 0 || s[i - 1] != '/')
+            .any(|i| matches(rest, &s[i..])),
+        Some((Token::Any, rest)) => (0..=s.len()).any(|i| matches(rest, &s[i..])),
+        Some((Token::AnyDirectories, rest)) => (0..=s.len())
+            .filter(|&i| i == 0 || s[i - 1] == '/')
+            .any(|i| matches(rest, &s[i..])),
+    }
+}
+
+impl Pattern {
+    /// `path` is relative to the directory being imported
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = if self.match_file_name_only {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        } else {
+            path.components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_string_lossy()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+        matches(&self.tokens, &path.chars().collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let pattern = |s: &str| s.parse::<Pattern>().unwrap();
+        assert!(pattern("*.o").matches(Path::new("drivers/net.o")));
+        assert!(!pattern("*.o").matches(Path::new("drivers/net.c")));
+        assert!(pattern("drivers/*.efi").matches(Path::new("drivers/net.efi")));
+        assert!(!pattern("drivers/*.efi").matches(Path::new("drivers/x/net.efi")));
+        assert!(pattern("drivers/**.efi").matches(Path::new("drivers/x/net.efi")));
+        assert!(pattern("**/debug").matches(Path::new("debug")));
+        assert!(pattern("**/debug").matches(Path::new("a/b/debug")));
+        assert!(pattern("/fonts/font?.psf").matches(Path::new("fonts/font1.psf")));
+    }
+}
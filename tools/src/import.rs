@@ -0,0 +1,112 @@
+// Collecting the files and directories to include in a disk image from the local filesystem
+use crate::glob::Pattern;
+use anyhow::{anyhow, Context, Error};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+pub struct Options {
+    pub follow_symlinks: bool,
+    pub exclude: Vec<Pattern>,
+}
+
+impl Options {
+    fn is_excluded(&self, relative_path: &Path) -> bool {
+        self.exclude
+            .iter()
+            .any(|pattern| pattern.matches(relative_path))
+    }
+}
+
+#[derive(Default)]
+pub struct Import {
+    pub path_pairs: Vec<mini_fat::PathPair>,
+    pub directories: Vec<PathBuf>,
+}
+
+impl Import {
+    /// If `in_local_filesystem` is a directory, its contents are imported recursively into the
+    /// directory `in_disk_image`
+    pub fn add(
+        &mut self,
+        in_local_filesystem: &Path,
+        in_disk_image: &Path,
+        options: &Options,
+    ) -> Result<(), Error> {
+        let metadata = fs::metadata(in_local_filesystem)
+            .with_context(|| format!("failed to access {}", in_local_filesystem.display()))?;
+        if metadata.is_dir() {
+            self.add_directory(
+                in_local_filesystem,
+                in_disk_image,
+                Path::new(""),
+                options,
+                &mut Vec::new(),
+            )
+        } else {
+            self.add_file(in_local_filesystem, in_disk_image)
+        }
+    }
+
+    fn add_file(&mut self, in_local_filesystem: &Path, in_disk_image: &Path) -> Result<(), Error> {
+        let file = File::open(in_local_filesystem)
+            .with_context(|| format!("failed to open {}", in_local_filesystem.display()))?;
+        self.path_pairs.push(mini_fat::PathPair {
+            in_local_filesystem: file,
+            in_disk_image: in_disk_image.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    fn add_directory(
+        &mut self,
+        in_local_filesystem: &Path,
+        in_disk_image: &Path,
+        relative_path: &Path,
+        options: &Options,
+        ancestors: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        // following symlinks can lead back to a directory which is already being imported
+        let canonical = fs::canonicalize(in_local_filesystem)?;
+        if ancestors.contains(&canonical) {
+            return Err(anyhow!(
+                "symlink cycle at {}",
+                in_local_filesystem.display()
+            ));
+        }
+        // the root directory always exists so can't be created explicitly
+        if in_disk_image.parent().is_some() {
+            self.directories.push(in_disk_image.to_path_buf());
+        }
+        let mut entries = fs::read_dir(in_local_filesystem)
+            .and_then(|read_dir| read_dir.collect::<Result<Vec<_>, _>>())
+            .with_context(|| {
+                format!("failed to read directory {}", in_local_filesystem.display())
+            })?;
+        // sort for consistent output regardless of the order the local filesystem lists entries
+        entries.sort_by_key(|entry| entry.file_name());
+        ancestors.push(canonical);
+        for entry in entries {
+            let name = entry.file_name();
+            let relative_path = relative_path.join(&name);
+            if options.is_excluded(&relative_path) {
+                continue;
+            }
+            if entry.file_type()?.is_symlink() && !options.follow_symlinks {
+                continue;
+            }
+            let local_path = entry.path();
+            let disk_path = in_disk_image.join(&name);
+            let metadata = fs::metadata(&local_path)
+                .with_context(|| format!("failed to access {}", local_path.display()))?;
+            if metadata.is_dir() {
+                self.add_directory(&local_path, &disk_path, &relative_path, options, ancestors)?;
+            } else if metadata.is_file() {
+                self.add_file(&local_path, &disk_path)?;
+            } else {
+                eprintln!("Warning: skipping special file {}", local_path.display());
+            }
+        }
+        ancestors.pop();
+        Ok(())
+    }
+}
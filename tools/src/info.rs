@@ -1,4 +1,4 @@
-use mini_fat::FatInfo;
+use mini_fat::{FatInfo, FatMismatch};
 use mini_gpt::GptInfo;
 use std::fmt;
 
@@ -34,18 +34,62 @@ impl Args {
 struct DisplayInfo {
     gpt_info: Option<GptInfo>,
     fat_info: FatInfo,
+    fat_mismatches: Vec<FatMismatch>,
 }
 
 impl fmt::Display for DisplayInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use mini_fat::FatType;
+        if let Some(ref gpt_info) = self.gpt_info {
+            writeln!(f, "Disk GUID: {}", gpt_info.disk_guid())?;
+            let usable_lba_range = gpt_info.usable_lba_range();
+            writeln!(
+                f,
+                "Usable LBAs: {}..={}",
+                usable_lba_range.start(),
+                usable_lba_range.end()
+            )?;
+            if let Some(e) = gpt_info.backup_header_error() {
+                writeln!(f, "Backup GPT: {}", e)?;
+            }
+            writeln!(f, "Partitions:")?;
+            for partition in gpt_info.partitions() {
+                writeln!(
+                    f,
+                    "  {}: \"{}\" type: {} guid: {} lba: {}..={} attributes: {:#x}",
+                    partition.index(),
+                    partition.name(),
+                    partition.partition_type_guid(),
+                    partition.unique_partition_guid(),
+                    partition.starting_lba(),
+                    partition.ending_lba(),
+                    partition.attributes(),
+                )?;
+            }
+        }
         write!(f, "FAT Type: ")?;
         match self.fat_info.fat_type() {
             FatType::Fat12 => writeln!(f, "FAT12")?,
             FatType::Fat16 => writeln!(f, "FAT16")?,
             FatType::Fat32 => writeln!(f, "FAT32")?,
         }
+        writeln!(f, "Volume Label: {}", self.fat_info.volume_label())?;
+        writeln!(f, "Volume ID: {:08X}", self.fat_info.volume_id())?;
+        writeln!(f, "OEM Name: {}", self.fat_info.oem_name())?;
         writeln!(f, "Num Clusters: {}", self.fat_info.num_clusters())?;
+        writeln!(f, "Num FATs: {}", self.fat_info.num_fats())?;
+        if self.fat_info.mirroring_disabled() {
+            writeln!(f, "Active FAT: {}", self.fat_info.active_fat())?;
+        }
+        if !self.fat_mismatches.is_empty() {
+            writeln!(f, "Differing FAT Entries: {}", self.fat_mismatches.len())?;
+        }
+        if let Some(num_free_clusters) = self.fat_info.num_free_clusters_hint() {
+            writeln!(f, "Num Free Clusters: {}", num_free_clusters)?;
+        }
+        if let Some(warning) = self.fat_info.fs_info_warning() {
+            writeln!(f, "FsInfo Warning: {:?}", warning)?;
+        }
         Ok(())
     }
 }
@@ -69,10 +113,18 @@ fn main() {
         )
     };
     let fat_info = error::or_die(mini_fat::fat_info(
+        &mut image_file,
+        first_partition_byte_range.clone(),
+    ));
+    let fat_mismatches = error::or_die(mini_fat::fat_mismatches(
         &mut image_file,
         first_partition_byte_range,
     ));
-    let display_info = DisplayInfo { gpt_info, fat_info };
+    let display_info = DisplayInfo {
+        gpt_info,
+        fat_info,
+        fat_mismatches,
+    };
     if debug {
         println!("{:#?}", display_info);
     } else {
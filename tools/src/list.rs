@@ -5,6 +5,7 @@ mod error;
 
 struct Args {
     image_filename: String,
+    exact: bool,
     list_filename: String,
     partition_only: bool,
     recursive: bool,
@@ -16,6 +17,7 @@ impl Args {
         (meap::let_map! {
             let {
                 image_filename = opt_req("PATH", 'i').name("image").desc("path to disk image");
+                exact = flag('e').name("exact").desc("match file names case-sensitively");
                 list_filename = opt_opt("PATH", 'f').name("file").desc("path within image of file to list")
                     .with_default_parse("/");
                 partition_only = flag('p').name("partition-only").desc("expect image to be a partition instead of an entire disk");
@@ -25,6 +27,7 @@ impl Args {
             } in {
                 Self {
                     image_filename,
+                    exact,
                     list_filename,
                     partition_only,
                     recursive,
@@ -41,6 +44,7 @@ fn main() {
     use std::fs::File;
     let Args {
         image_filename,
+        exact,
         list_filename,
         partition_only,
         recursive,
@@ -56,7 +60,12 @@ fn main() {
     let mut reader = error::or_die(mini_fat::FatReader::new(
         &mut image_file,
         first_partition_byte_range,
-    ));
+    ))
+    .with_name_match(if exact {
+        mini_fat::NameMatch::Exact
+    } else {
+        mini_fat::NameMatch::CaseInsensitive
+    });
     error::or_die(if recursive {
         recursive_list(&mut reader, &list_filename, show_current_and_parent)
     } else {
@@ -129,13 +138,11 @@ fn recursive_list<H: io::Seek + io::Read, P: AsRef<path::Path>>(
                     if !is_current_or_parent(name) || show_current_and_parent {
                         println!("{}", name);
                     }
-                    if e.is_directory() {
-                        if !is_current_or_parent(name) {
-                            queue.push_back(format!("{}/{}", format_path(&path), name).into());
-                        }
+                    if e.is_directory() && !is_current_or_parent(name) {
+                        queue.push_back(format!("{}/{}", format_path(&path), name).into());
                     }
                 }
-                println!("");
+                println!();
             }
         }
     }
@@ -0,0 +1,425 @@
+// Declarative descriptions of disk images. A manifest is a sequence of `[partition]` sections,
+// each containing `key = value` lines. Blank lines and lines starting with `#` are ignored.
+//
+//     [partition]
+//     name = efi
+//     type = efi-system
+//     guid = 3F7D5F38-6C3E-4D7B-9C8A-1E2F3A4B5C6D
+//     label = BOOT
+//     volume-id = 1234-ABCD
+//     oem-name = MSWIN4.1
+//     fat-type = 16
+//     sector-size = 512
+//     sectors-per-cluster = 4
+//     free = 25%
+//     file = build/esp -> /
+//     file = build/kernel.elf -> /kernel.elf
+//     directory = /logs
+//     exclude = *.o
+//     hidden = /logs
+//     system = /kernel.elf
+//     timestamp = 1600000000
+//
+//     [partition]
+//     name = root
+//     type = linux-root-x86-64
+//     size = 1GiB
+//
+// Every partition must have a `name` and a `type`. Partitions are formatted as FAT if they have any
+// of `file`, `directory`, `label`, `free` or one of the FAT formatting options (`volume-id`,
+// `oem-name`, `fat-type`, `sector-size`, `sectors-per-cluster`, `hidden`, `system` or `timestamp`),
+// or if `filesystem = fat` is given. Otherwise they are left blank and must specify a `size`. FAT
+// partitions are sized to fit their contents, plus any `free` space (in bytes or as a percentage of
+// the size of the contents), unless they specify a `size`. Local paths are relative to the
+// directory containing the manifest. Partitions without a `guid` are given a random unique GUID, or
+// one derived from the seed when creating reproducible images. Files take their timestamps from the
+// local filesystem unless a fixed `timestamp` (in seconds since the unix epoch) is given.
+use crate::glob::Pattern;
+use anyhow::{anyhow, Context, Error};
+use mini_fat::{FatType, PartitionSize};
+use mini_gpt::{partition_type_guid, Uuid};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const PARTITION_SECTION: &str = "[partition]";
+
+pub struct FileMapping {
+    pub in_local_filesystem: PathBuf,
+    pub in_disk_image: PathBuf,
+    pub line: usize,
+}
+
+pub struct Partition {
+    pub line: usize,
+    pub name: String,
+    pub partition_type_guid: Uuid,
+    pub unique_partition_guid: Option<Uuid>,
+    pub size_in_bytes: Option<u64>,
+    pub free_space: Option<PartitionSize>,
+    pub fat: bool,
+    pub volume_label: Option<String>,
+    pub volume_id: Option<u32>,
+    pub oem_name: Option<String>,
+    pub fat_type: Option<FatType>,
+    pub bytes_per_sector: Option<u16>,
+    pub sectors_per_cluster: Option<u8>,
+    pub files: Vec<FileMapping>,
+    pub directories: Vec<PathBuf>,
+    pub exclude: Vec<Pattern>,
+    pub hidden: Vec<PathBuf>,
+    pub system: Vec<PathBuf>,
+    pub timestamp: Option<SystemTime>,
+}
+
+pub struct Manifest {
+    pub partitions: Vec<Partition>,
+}
+
+fn parse_partition_type(s: &str) -> Result<Uuid, String> {
+    let guid = match s {
+        "efi-system" => partition_type_guid::EFI_SYSTEM_PARTITION,
+        "linux-filesystem" => partition_type_guid::LINUX_FILESYSTEM_DATA,
+        "linux-root-x86-64" => partition_type_guid::LINUX_ROOT_X86_64,
+        "linux-root-aarch64" => partition_type_guid::LINUX_ROOT_AARCH64,
+        "linux-swap" => partition_type_guid::LINUX_SWAP,
+        "microsoft-basic-data" => partition_type_guid::MICROSOFT_BASIC_DATA,
+        other => other,
+    };
+    Uuid::parse_str(guid).map_err(|_| format!("unknown partition type \"{}\"", s))
+}
+
+pub fn parse_guid(s: &str) -> Result<Uuid, String> {
+    Uuid::parse_str(s).map_err(|_| format!("invalid GUID \"{}\"", s))
+}
+
+pub fn parse_fat_type(s: &str) -> Result<FatType, String> {
+    match s {
+        "12" => Ok(FatType::Fat12),
+        "16" => Ok(FatType::Fat16),
+        "32" => Ok(FatType::Fat32),
+        _ => Err(format!(
+            "invalid FAT type \"{}\" (expected 12, 16 or 32)",
+            s
+        )),
+    }
+}
+
+/// Sizes are a number of bytes with an optional binary unit suffix (eg. "64MiB" or "64M")
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits_end);
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        _ => return Err(format!("invalid size \"{}\"", s)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size \"{}\"", s))
+}
+
+/// Free space is a size (see `parse_size`) or a percentage (eg. "25%")
+pub fn parse_free_space(s: &str) -> Result<PartitionSize, String> {
+    match s.strip_suffix('%') {
+        Some(percent) => percent
+            .trim()
+            .parse()
+            .map(PartitionSize::FreePercent)
+            .map_err(|_| format!("invalid percentage \"{}\"", s)),
+        None => parse_size(s).map(PartitionSize::FreeBytes),
+    }
+}
+
+#[derive(Default)]
+struct PartitionSection {
+    line: usize,
+    name: Option<String>,
+    partition_type_guid: Option<Uuid>,
+    unique_partition_guid: Option<Uuid>,
+    size_in_bytes: Option<u64>,
+    free_space: Option<PartitionSize>,
+    fat: Option<bool>,
+    volume_label: Option<String>,
+    volume_id: Option<u32>,
+    oem_name: Option<String>,
+    fat_type: Option<FatType>,
+    bytes_per_sector: Option<u16>,
+    sectors_per_cluster: Option<u8>,
+    files: Vec<FileMapping>,
+    directories: Vec<PathBuf>,
+    exclude: Vec<Pattern>,
+    hidden: Vec<PathBuf>,
+    system: Vec<PathBuf>,
+    timestamp: Option<SystemTime>,
+}
+
+/// Volume IDs are written in hex, optionally split in half with a "-" as they are usually displayed
+pub fn parse_volume_id(s: &str) -> Result<u32, String> {
+    let hex = match s.split_once('-') {
+        Some((high, low)) if high.len() == 4 && low.len() == 4 => format!("{}{}", high, low),
+        Some(_) => return Err(format!("invalid volume id \"{}\"", s)),
+        None => s.to_string(),
+    };
+    if hex.is_empty() || hex.len() > 8 {
+        return Err(format!("invalid volume id \"{}\"", s));
+    }
+    u32::from_str_radix(&hex, 16).map_err(|_| format!("invalid volume id \"{}\"", s))
+}
+
+/// Timestamps are a number of seconds since the unix epoch
+pub fn parse_timestamp(s: &str) -> Result<SystemTime, String> {
+    s.parse()
+        .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+        .map_err(|_| format!("invalid timestamp \"{}\"", s))
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid number \"{}\"", s))
+}
+
+fn set_once<T>(field: &mut Option<T>, key: &str, value: T) -> Result<(), String> {
+    if field.is_some() {
+        return Err(format!("\"{}\" may only be given once per partition", key));
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+impl PartitionSection {
+    fn set(&mut self, key: &str, value: &str, line: usize, base: &Path) -> Result<(), String> {
+        match key {
+            "name" => set_once(&mut self.name, key, value.to_string()),
+            "type" => set_once(
+                &mut self.partition_type_guid,
+                key,
+                parse_partition_type(value)?,
+            ),
+            "guid" => set_once(&mut self.unique_partition_guid, key, parse_guid(value)?),
+            "size" => set_once(&mut self.size_in_bytes, key, parse_size(value)?),
+            "free" => set_once(&mut self.free_space, key, parse_free_space(value)?),
+            "filesystem" => {
+                let fat = match value {
+                    "fat" => true,
+                    "none" => false,
+                    _ => return Err(format!("unknown filesystem \"{}\"", value)),
+                };
+                set_once(&mut self.fat, key, fat)
+            }
+            "label" => set_once(&mut self.volume_label, key, value.to_string()),
+            "volume-id" => set_once(&mut self.volume_id, key, parse_volume_id(value)?),
+            "oem-name" => set_once(&mut self.oem_name, key, value.to_string()),
+            "fat-type" => set_once(&mut self.fat_type, key, parse_fat_type(value)?),
+            "sector-size" => set_once(&mut self.bytes_per_sector, key, parse_number(value)?),
+            "sectors-per-cluster" => {
+                set_once(&mut self.sectors_per_cluster, key, parse_number(value)?)
+            }
+            "file" => {
+                let (local, disk) = value
+                    .split_once("->")
+                    .ok_or_else(|| "expected \"file = LOCAL_PATH -> DISK_PATH\"".to_string())?;
+                self.files.push(FileMapping {
+                    in_local_filesystem: base.join(local.trim()),
+                    in_disk_image: PathBuf::from(disk.trim()),
+                    line,
+                });
+                Ok(())
+            }
+            "directory" => {
+                self.directories.push(PathBuf::from(value));
+                Ok(())
+            }
+            "exclude" => {
+                self.exclude.push(value.parse()?);
+                Ok(())
+            }
+            "hidden" => {
+                self.hidden.push(PathBuf::from(value));
+                Ok(())
+            }
+            "system" => {
+                self.system.push(PathBuf::from(value));
+                Ok(())
+            }
+            "timestamp" => set_once(&mut self.timestamp, key, parse_timestamp(value)?),
+            _ => Err(format!("unknown key \"{}\"", key)),
+        }
+    }
+
+    fn finish(self) -> Result<Partition, String> {
+        let has_contents = !self.files.is_empty()
+            || !self.directories.is_empty()
+            || self.volume_label.is_some()
+            || self.volume_id.is_some()
+            || self.oem_name.is_some()
+            || self.free_space.is_some()
+            || self.fat_type.is_some()
+            || self.bytes_per_sector.is_some()
+            || self.sectors_per_cluster.is_some()
+            || !self.hidden.is_empty()
+            || !self.system.is_empty()
+            || self.timestamp.is_some();
+        let fat = self.fat.unwrap_or(has_contents);
+        if !fat && has_contents {
+            return Err("partition with \"filesystem = none\" may not have contents".to_string());
+        }
+        if self.size_in_bytes.is_some() && self.free_space.is_some() {
+            return Err("\"size\" and \"free\" may not both be given".to_string());
+        }
+        if !fat && self.size_in_bytes.is_none() {
+            return Err("partition without a filesystem must specify a size".to_string());
+        }
+        Ok(Partition {
+            line: self.line,
+            name: self
+                .name
+                .ok_or_else(|| "partition must specify a name".to_string())?,
+            partition_type_guid: self
+                .partition_type_guid
+                .ok_or_else(|| "partition must specify a type".to_string())?,
+            unique_partition_guid: self.unique_partition_guid,
+            size_in_bytes: self.size_in_bytes,
+            free_space: self.free_space,
+            fat,
+            volume_label: self.volume_label,
+            volume_id: self.volume_id,
+            oem_name: self.oem_name,
+            fat_type: self.fat_type,
+            bytes_per_sector: self.bytes_per_sector,
+            sectors_per_cluster: self.sectors_per_cluster,
+            files: self.files,
+            directories: self.directories,
+            exclude: self.exclude,
+            hidden: self.hidden,
+            system: self.system,
+            timestamp: self.timestamp,
+        })
+    }
+}
+
+impl Manifest {
+    /// Errors are reported with the line of the manifest that caused them
+    fn parse(text: &str, base: &Path) -> Result<Self, (usize, String)> {
+        let mut partitions = Vec::new();
+        let mut current: Option<PartitionSection> = None;
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                if line != PARTITION_SECTION {
+                    return Err((line_number, format!("unknown section \"{}\"", line)));
+                }
+                if let Some(section) = current.take() {
+                    let section_line = section.line;
+                    partitions.push(section.finish().map_err(|e| (section_line, e))?);
+                }
+                current = Some(PartitionSection {
+                    line: line_number,
+                    ..Default::default()
+                });
+                continue;
+            }
+            let section = current.as_mut().ok_or_else(|| {
+                (
+                    line_number,
+                    format!("expected \"{}\" before any settings", PARTITION_SECTION),
+                )
+            })?;
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| (line_number, "expected \"KEY = VALUE\"".to_string()))?;
+            section
+                .set(key.trim(), value.trim(), line_number, base)
+                .map_err(|e| (line_number, e))?;
+        }
+        if let Some(section) = current {
+            let section_line = section.line;
+            partitions.push(section.finish().map_err(|e| (section_line, e))?);
+        }
+        if partitions.is_empty() {
+            return Err((1, "manifest contains no partitions".to_string()));
+        }
+        Ok(Self { partitions })
+    }
+
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read manifest {}", path.display()))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, base)
+            .map_err(|(line, message)| anyhow!("{}:{}: {}", path.display(), line, message))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let text = "
+            # boot partition
+            [partition]
+            name = efi
+            type = efi-system
+            file = build/esp -> /
+            directory = /logs
+            hidden = /logs
+            timestamp = 1600000000
+
+            [partition]
+            name = data
+            type = 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+            guid = 3F7D5F38-6C3E-4D7B-9C8A-1E2F3A4B5C6D
+            size = 16MiB
+        ";
+        let manifest = Manifest::parse(text, Path::new("base")).unwrap();
+        assert_eq!(manifest.partitions.len(), 2);
+        let efi = &manifest.partitions[0];
+        assert!(efi.fat);
+        assert_eq!(
+            efi.files[0].in_local_filesystem,
+            Path::new("base/build/esp")
+        );
+        assert_eq!(efi.files[0].line, 6);
+        assert_eq!(efi.hidden, vec![PathBuf::from("/logs")]);
+        assert_eq!(
+            efi.timestamp,
+            Some(UNIX_EPOCH + Duration::from_secs(1600000000))
+        );
+        let data = &manifest.partitions[1];
+        assert!(!data.fat);
+        assert_eq!(data.size_in_bytes, Some(16 << 20));
+        assert_eq!(efi.unique_partition_guid, None);
+        assert_eq!(
+            data.unique_partition_guid,
+            Some(Uuid::parse_str("3F7D5F38-6C3E-4D7B-9C8A-1E2F3A4B5C6D").unwrap())
+        );
+        let error = |text| Manifest::parse(text, Path::new("")).err().unwrap();
+        assert_eq!(error("[partition]\ntype = efi-system\nsize = lots").0, 3);
+        assert_eq!(error("\n[partition]\nname = x\n").0, 2);
+        assert_eq!(error("\n[partition]\ntype = efi-system\n").0, 2);
+        assert_eq!(error("name = x").0, 1);
+        assert_eq!(
+            error("[partition]\ntype = efi-system\nsize = 1M\nfree = 10%").0,
+            1
+        );
+        assert_eq!(parse_free_space("25%"), Ok(PartitionSize::FreePercent(25)));
+        assert_eq!(
+            parse_free_space("1MiB"),
+            Ok(PartitionSize::FreeBytes(1 << 20))
+        );
+        assert_eq!(parse_volume_id("1234-ABCD"), Ok(0x1234ABCD));
+        assert_eq!(parse_volume_id("1234abcd"), Ok(0x1234ABCD));
+        assert!(parse_volume_id("123-4ABCD").is_err());
+        assert!(parse_volume_id("123456789").is_err());
+    }
+}
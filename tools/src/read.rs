@@ -5,6 +5,7 @@ mod error;
 
 struct Args {
     image_filename: String,
+    exact: bool,
     read_filename: String,
     output: Box<dyn io::Write>,
 }
@@ -14,11 +15,13 @@ impl Args {
         (meap::let_map! {
             let {
                 image_filename = opt_req("PATH", 'i').name("image").desc("path to disk image");
+                exact = flag('e').name("exact").desc("match file names case-sensitively");
                 read_filename = opt_req("PATH", 'f').name("file").desc("path within image of file to read");
                 output = opt_opt::<String, _>("PATH", 'o').name("output").desc("output file path (omit for stdout)");
             } in {
                 Self {
                     image_filename,
+                    exact,
                     read_filename,
                     output: if let Some(path) = output {
                         Box::new(File::create(path).unwrap())
@@ -36,6 +39,7 @@ impl Args {
 fn main() {
     let Args {
         image_filename,
+        exact,
         read_filename,
         mut output,
     } = Args::parse();
@@ -46,6 +50,11 @@ fn main() {
     let mut reader = error::or_die(mini_fat::FatReader::new(
         &mut image_file,
         first_partition_byte_range,
-    ));
+    ))
+    .with_name_match(if exact {
+        mini_fat::NameMatch::Exact
+    } else {
+        mini_fat::NameMatch::CaseInsensitive
+    });
     error::or_die(reader.read(&read_filename, &mut output));
 }